@@ -113,6 +113,13 @@ pub const BROTLI_OPERATION_FLUSH: BrotliEncoderOperation = 1;
 pub const BROTLI_OPERATION_FINISH: BrotliEncoderOperation = 2;
 pub const BROTLI_OPERATION_EMIT_METADATA: BrotliEncoderOperation = 3;
 
+pub const BROTLI_MIN_WINDOW_BITS: u32 = 10;
+pub const BROTLI_MAX_WINDOW_BITS: u32 = 24;
+pub const BROTLI_MIN_INPUT_BLOCK_BITS: u32 = 16;
+pub const BROTLI_MAX_INPUT_BLOCK_BITS: u32 = 24;
+pub const BROTLI_MIN_QUALITY: u32 = 0;
+pub const BROTLI_MAX_QUALITY: u32 = 11;
+
 pub const BROTLI_DEFAULT_QUALITY: u32 = 11;
 pub const BROTLI_DEFAULT_WINDOW: u32 = 22;
 pub const BROTLI_DEFAULT_MODE: u32 = 0;
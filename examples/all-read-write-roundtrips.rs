@@ -17,7 +17,7 @@ fn main() {
     let v2 = vec![44; 10 * 1024 * 1024];
     let datas: &[&[u8]] = &[b"", b"a", b"aaaaa", b";", &v1, &v2];
     let mut params = CompressParams::new();
-    params.quality(6);
+    params.quality(6).unwrap();
     let params = &params;
 
     fn bufencode(data: &[u8], params: &CompressParams) -> Vec<u8> {
@@ -37,7 +37,7 @@ fn main() {
         read::BrotliEncoder::from_params(data, params)
             .read_to_end(&mut buf)
             .unwrap();
-        assert!(buf.len() > 0);
+        assert!(!buf.is_empty());
         buf
     }
     fn ioreaddecode(data: &[u8]) -> Vec<u8> {
@@ -0,0 +1,147 @@
+//! Custom memory allocators for compressors and decompressors
+//!
+//! By default libbrotli allocates its internal state with `malloc`. An
+//! `Allocator` passed to `Compress::with_allocator` or
+//! `Decompress::with_allocator` is used for all of the state's allocations
+//! instead, so that brotli's memory can be attributed to an arena or
+//! accounted for separately.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::ptr;
+
+/// A memory allocator for the internal state of libbrotli.
+///
+/// `alloc` may fail by returning null, but only decompressors handle that:
+/// the decompression fails with an allocation error. A null returned to a
+/// compressor aborts the process through `std::alloc::handle_alloc_error`.
+///
+/// # Safety
+///
+/// `alloc` must return either null or a pointer to at least `size` bytes of
+/// memory aligned for any type, which stays valid until it is passed to
+/// `free`. Neither method may panic, as they are called from C.
+pub unsafe trait Allocator: Send + Sync {
+    /// Allocates `size` bytes of memory, returning null on failure.
+    fn alloc(&self, size: usize) -> *mut u8;
+
+    /// Frees memory previously returned by `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this allocator, and must
+    /// not be used afterwards.
+    unsafe fn free(&self, ptr: *mut u8);
+}
+
+/// Adapts a `GlobalAlloc`, such as `std::alloc::System` or jemalloc, into an
+/// `Allocator`.
+///
+/// libbrotli doesn't pass the size of the block when freeing it, so each
+/// allocation is prefixed with a small header recording its size.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalAllocator<A>(pub A);
+
+/// Size of the header in front of each block, which also keeps the block
+/// suitably aligned.
+const HEADER: usize = 16;
+
+impl<A> GlobalAllocator<A> {
+    fn layout(size: usize) -> Option<Layout> {
+        let size = size.checked_add(HEADER)?;
+        Layout::from_size_align(size, HEADER).ok()
+    }
+}
+
+unsafe impl<A: GlobalAlloc + Send + Sync> Allocator for GlobalAllocator<A> {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let layout = match GlobalAllocator::<A>::layout(size) {
+            Some(layout) => layout,
+            None => return ptr::null_mut(),
+        };
+        unsafe {
+            let block = self.0.alloc(layout);
+            if block.is_null() {
+                return block;
+            }
+            (block as *mut usize).write(size);
+            block.add(HEADER)
+        }
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let block = ptr.sub(HEADER);
+        let size = (block as *const usize).read();
+        self.0
+            .dealloc(block, GlobalAllocator::<A>::layout(size).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Allocator, GlobalAllocator};
+    use raw::{CoStatus, Compress, CompressOp, DeStatus, Decompress};
+    use std::alloc::System;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use {CompressParams, DecompressParams};
+
+    #[derive(Default)]
+    struct Counting {
+        inner: GlobalAllocator<System>,
+        live: AtomicUsize,
+        total: AtomicUsize,
+    }
+
+    unsafe impl Allocator for Counting {
+        fn alloc(&self, size: usize) -> *mut u8 {
+            self.live.fetch_add(1, Ordering::SeqCst);
+            self.total.fetch_add(1, Ordering::SeqCst);
+            self.inner.alloc(size)
+        }
+
+        unsafe fn free(&self, ptr: *mut u8) {
+            if !ptr.is_null() {
+                self.live.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.inner.free(ptr)
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let allocator = Arc::new(Counting::default());
+        let input = "custom allocator ".repeat(100);
+        {
+            let mut c = Compress::with_allocator(&CompressParams::new(), allocator.clone());
+            let mut d = Decompress::with_allocator(&DecompressParams::new(), allocator.clone());
+            for _ in 0..2 {
+                let mut data = vec![0; 1024];
+                let n = {
+                    let mut out = &mut data[..];
+                    assert_eq!(
+                        c.compress(CompressOp::Finish, &mut input.as_bytes(), &mut out),
+                        Ok(CoStatus::Finished)
+                    );
+                    1024 - out.len()
+                };
+                let mut dst = vec![0; input.len()];
+                assert_eq!(
+                    d.decompress(&mut &data[..n], &mut &mut dst[..]),
+                    Ok(DeStatus::Finished)
+                );
+                assert_eq!(dst, input.as_bytes());
+
+                // The allocator survives a reset.
+                let total = allocator.total.load(Ordering::SeqCst);
+                c.reset();
+                d.reset();
+                assert!(allocator.total.load(Ordering::SeqCst) > total);
+            }
+            assert!(allocator.live.load(Ordering::SeqCst) > 0);
+        }
+        assert_eq!(allocator.live.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,338 @@
+//! Async streams for wrapping `AsyncBufRead` types as encoders/decoders
+
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::PollBufRead;
+use codec::{Decoder, Encoder, BUF_SIZE};
+use dictionary::SharedDictionary;
+use raw::{Compress, Decompress};
+use {level_params, CompressParams, DecompressParams};
+
+/// A brotli encoder, or compressor.
+///
+/// This structure implements the `AsyncRead` and `AsyncBufRead` interfaces
+/// and will read uncompressed data from an underlying stream and emit a
+/// stream of compressed data.
+pub struct BrotliEncoder<R> {
+    obj: R,
+    data: Encoder,
+    out: OutBuf,
+}
+
+/// A brotli decoder, or decompressor.
+///
+/// This structure implements the `AsyncRead` and `AsyncBufRead` interfaces
+/// and takes a stream of compressed data as input, providing the decompressed
+/// data when read from.
+pub struct BrotliDecoder<R> {
+    obj: R,
+    data: Decoder,
+    out: OutBuf,
+}
+
+/// Output produced for `poll_fill_buf` which hasn't been consumed yet.
+///
+/// Plain reads go straight to the caller's buffer, so this is only allocated
+/// once `poll_fill_buf` is used.
+struct OutBuf {
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl OutBuf {
+    fn new() -> OutBuf {
+        OutBuf {
+            buf: Vec::new(),
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    /// Copies buffered output into `buf`, returning `None` if there is none.
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.pos == self.cap {
+            return None;
+        }
+        let n = cmp::min(buf.len(), self.cap - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Some(n)
+    }
+
+    /// Returns the buffered output, calling `fill` to produce more if it has
+    /// all been consumed.
+    fn poll_fill<F>(&mut self, fill: F) -> Poll<io::Result<&[u8]>>
+    where
+        F: FnOnce(&mut [u8]) -> Poll<io::Result<usize>>,
+    {
+        if self.pos == self.cap {
+            if self.buf.is_empty() {
+                self.buf = vec![0; BUF_SIZE];
+            }
+            self.cap = ready!(fill(&mut self.buf))?;
+            self.pos = 0;
+        }
+        Poll::Ready(Ok(&self.buf[self.pos..self.cap]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+/// Compresses input from `obj` into `buf`.
+fn poll_encode<Rt, R: PollBufRead<Rt> + Unpin>(
+    cx: &mut Context,
+    data: &mut Encoder,
+    obj: &mut R,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    if let Some(n) = data.read_pending(buf)? {
+        return Poll::Ready(Ok(n));
+    }
+    loop {
+        let input = ready!(Pin::new(&mut *obj).poll_fill_buf(cx))?;
+        let (amt_in, amt_out) = data.read(input, buf)?;
+        Pin::new(&mut *obj).consume(amt_in);
+        if let Some(n) = amt_out {
+            return Poll::Ready(Ok(n));
+        }
+    }
+}
+
+/// Decompresses input from `obj` into `buf`.
+fn poll_decode<Rt, R: PollBufRead<Rt> + Unpin>(
+    cx: &mut Context,
+    data: &mut Decoder,
+    obj: &mut R,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    data.check()?;
+    loop {
+        let input = ready!(Pin::new(&mut *obj).poll_fill_buf(cx))?;
+        let (amt_in, amt_out) = data.read(input, buf)?;
+        Pin::new(&mut *obj).consume(amt_in);
+        if let Some(n) = amt_out {
+            return Poll::Ready(Ok(n));
+        }
+    }
+}
+
+impl<R> BrotliEncoder<R> {
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream and emit the compressed stream.
+    ///
+    /// The `level` argument here is typically 0-11.
+    pub fn new(r: R, level: u32) -> BrotliEncoder<R> {
+        BrotliEncoder::from_params(r, &level_params(level))
+    }
+
+    /// Creates a new encoder with a custom `CompressParams`.
+    pub fn from_params(r: R, params: &CompressParams) -> BrotliEncoder<R> {
+        let mut data = Compress::new();
+        data.set_params(params).unwrap();
+        BrotliEncoder {
+            obj: r,
+            data: Encoder::new(data),
+            out: OutBuf::new(),
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this encoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.obj
+    }
+
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this encoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.obj
+    }
+}
+
+impl<R: Unpin> BrotliEncoder<R> {
+    fn poll_read_buf<Rt>(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>
+    where
+        R: PollBufRead<Rt>,
+    {
+        if let Some(n) = self.out.read(buf) {
+            return Poll::Ready(Ok(n));
+        }
+        poll_encode(cx, &mut self.data, &mut self.obj, buf)
+    }
+
+    fn poll_fill_out<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<&[u8]>>
+    where
+        R: PollBufRead<Rt>,
+    {
+        let BrotliEncoder { obj, data, out } = self;
+        out.poll_fill(|buf| poll_encode(cx, data, obj, buf))
+    }
+}
+
+impl<R> BrotliDecoder<R> {
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream.
+    pub fn new(r: R) -> BrotliDecoder<R> {
+        BrotliDecoder::from_params(r, &DecompressParams::new())
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using a custom dictionary.
+    ///
+    /// The stream must have been compressed with the same dictionary.
+    pub fn with_dictionary(r: R, dictionary: &[u8]) -> BrotliDecoder<R> {
+        BrotliDecoder::with_data(r, Decompress::with_dictionary(dictionary))
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using a shared custom dictionary.
+    pub fn with_shared_dictionary(r: R, dictionary: &SharedDictionary) -> BrotliDecoder<R> {
+        BrotliDecoder::with_data(r, Decompress::with_shared_dictionary(dictionary))
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, with custom decompression parameters.
+    pub fn from_params(r: R, params: &DecompressParams) -> BrotliDecoder<R> {
+        BrotliDecoder::with_data(r, Decompress::from_params(params))
+    }
+
+    fn with_data(r: R, data: Decompress) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            obj: r,
+            data: Decoder::new(data),
+            out: OutBuf::new(),
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.obj
+    }
+
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.obj
+    }
+}
+
+impl<R: Unpin> BrotliDecoder<R> {
+    fn poll_read_buf<Rt>(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>
+    where
+        R: PollBufRead<Rt>,
+    {
+        if let Some(n) = self.out.read(buf) {
+            return Poll::Ready(Ok(n));
+        }
+        poll_decode(cx, &mut self.data, &mut self.obj, buf)
+    }
+
+    fn poll_fill_out<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<&[u8]>>
+    where
+        R: PollBufRead<Rt>,
+    {
+        let BrotliDecoder { obj, data, out } = self;
+        out.poll_fill(|buf| poll_decode(cx, data, obj, buf))
+    }
+}
+
+/// Implements the `AsyncRead` and `AsyncBufRead` traits of tokio and
+/// futures-io for a stream with `poll_read_buf` and `poll_fill_out`.
+macro_rules! impl_async_read {
+    ($ty:ident) => {
+        #[cfg(feature = "tokio")]
+        impl<R: tokio_crate::io::AsyncBufRead + Unpin> tokio_crate::io::AsyncRead for $ty<R> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut tokio_crate::io::ReadBuf,
+            ) -> Poll<io::Result<()>> {
+                if buf.remaining() == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                let this = self.get_mut();
+                let n = ready!(this.poll_read_buf::<super::Tokio>(cx, buf.initialize_unfilled()))?;
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        impl<R: tokio_crate::io::AsyncBufRead + Unpin> tokio_crate::io::AsyncBufRead for $ty<R> {
+            fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+                self.get_mut().poll_fill_out::<super::Tokio>(cx)
+            }
+
+            fn consume(self: Pin<&mut Self>, amt: usize) {
+                self.get_mut().out.consume(amt)
+            }
+        }
+
+        #[cfg(feature = "futures-io")]
+        impl<R: futures_io::AsyncBufRead + Unpin> futures_io::AsyncRead for $ty<R> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                if buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                self.get_mut().poll_read_buf::<super::Futures>(cx, buf)
+            }
+        }
+
+        #[cfg(feature = "futures-io")]
+        impl<R: futures_io::AsyncBufRead + Unpin> futures_io::AsyncBufRead for $ty<R> {
+            fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+                self.get_mut().poll_fill_out::<super::Futures>(cx)
+            }
+
+            fn consume(self: Pin<&mut Self>, amt: usize) {
+                self.get_mut().out.consume(amt)
+            }
+        }
+    };
+}
+
+impl_async_read!(BrotliEncoder);
+impl_async_read!(BrotliDecoder);
@@ -0,0 +1,280 @@
+//! Async streams shared by the `tokio` and `futures` modules
+//!
+//! tokio and futures-io have their own, incompatible, I/O traits. The streams
+//! are written once here, against `PollBufRead` and `PollWrite`, which are
+//! implemented for any reader or writer implementing either crate's traits.
+//! The runtime they are used with is picked by the `Rt` marker, so a stream
+//! over a type implementing both crate's traits isn't ambiguous. Each stream
+//! then implements both crate's traits on top of the same poll functions, and
+//! the `tokio` and `futures` modules re-export them.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub mod bufread;
+pub mod write;
+
+/// Marker for streams driven through tokio's I/O traits.
+#[cfg(feature = "tokio")]
+pub enum Tokio {}
+
+/// Marker for streams driven through the `futures-io` traits.
+#[cfg(feature = "futures-io")]
+pub enum Futures {}
+
+/// An async buffered reader, as seen through the I/O traits of `Rt`.
+pub trait PollBufRead<Rt> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>>;
+    fn consume(self: Pin<&mut Self>, amt: usize);
+}
+
+/// An async writer, as seen through the I/O traits of `Rt`.
+pub trait PollWrite<Rt> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>>;
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>>;
+}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio_crate::io::AsyncBufRead + ?Sized> PollBufRead<Tokio> for T {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        tokio_crate::io::AsyncBufRead::poll_fill_buf(self, cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        tokio_crate::io::AsyncBufRead::consume(self, amt)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio_crate::io::AsyncWrite + ?Sized> PollWrite<Tokio> for T {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        tokio_crate::io::AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        tokio_crate::io::AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        tokio_crate::io::AsyncWrite::poll_shutdown(self, cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: futures_io::AsyncBufRead + ?Sized> PollBufRead<Futures> for T {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        futures_io::AsyncBufRead::poll_fill_buf(self, cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        futures_io::AsyncBufRead::consume(self, amt)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: futures_io::AsyncWrite + ?Sized> PollWrite<Futures> for T {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        futures_io::AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        futures_io::AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        futures_io::AsyncWrite::poll_close(self, cx)
+    }
+}
+
+/// Test harness shared by the tests of the `tokio` and `futures` modules.
+#[cfg(test)]
+pub mod tests {
+    use std::task::Context;
+
+    use rand::{thread_rng, Rng};
+
+    /// Returns `Pending` from every other call, and only moves a few bytes at
+    /// a time.
+    pub struct Stutter<T> {
+        pub inner: T,
+        pending: bool,
+    }
+
+    const CHUNK: usize = 7;
+
+    impl<T> Stutter<T> {
+        pub fn new(inner: T) -> Stutter<T> {
+            Stutter {
+                inner,
+                pending: false,
+            }
+        }
+
+        fn stutter(&mut self, cx: &mut Context) -> bool {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+            }
+            self.pending
+        }
+    }
+
+    pub fn random_data() -> Vec<u8> {
+        let mut rng = thread_rng();
+        let len = rng.gen_range(20_000, 100_000);
+        (0..len).map(|_| rng.gen_range(b'a', b'f')).collect()
+    }
+
+    /// Lines of text, for the `AsyncBufRead` tests.
+    pub fn lines() -> String {
+        (0..1000).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[cfg(feature = "tokio")]
+    mod tokio {
+        use super::{Stutter, CHUNK};
+        use std::cmp;
+        use std::io;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+        use tokio_crate::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+        impl<T: AsyncRead + Unpin> AsyncRead for Stutter<T> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut ReadBuf,
+            ) -> Poll<io::Result<()>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                let n = cmp::min(buf.remaining(), CHUNK);
+                let mut chunk = ReadBuf::new(buf.initialize_unfilled_to(n));
+                match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+                    Poll::Ready(Ok(())) => {
+                        let n = chunk.filled().len();
+                        buf.advance(n);
+                        Poll::Ready(Ok(()))
+                    }
+                    other => other,
+                }
+            }
+        }
+
+        impl<T: AsyncBufRead + Unpin> AsyncBufRead for Stutter<T> {
+            fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+                let this = self.get_mut();
+                if this.stutter(cx) {
+                    return Poll::Pending;
+                }
+                match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+                    Poll::Ready(Ok(buf)) => Poll::Ready(Ok(&buf[..cmp::min(buf.len(), CHUNK)])),
+                    other => other,
+                }
+            }
+
+            fn consume(mut self: Pin<&mut Self>, amt: usize) {
+                Pin::new(&mut self.inner).consume(amt)
+            }
+        }
+
+        impl<T: AsyncWrite + Unpin> AsyncWrite for Stutter<T> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                let n = cmp::min(buf.len(), CHUNK);
+                Pin::new(&mut self.inner).poll_write(cx, &buf[..n])
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.inner).poll_shutdown(cx)
+            }
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    mod futures {
+        use super::{Stutter, CHUNK};
+        use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+        use std::cmp;
+        use std::io;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        impl<T: AsyncRead + Unpin> AsyncRead for Stutter<T> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                let n = cmp::min(buf.len(), CHUNK);
+                Pin::new(&mut self.inner).poll_read(cx, &mut buf[..n])
+            }
+        }
+
+        impl<T: AsyncBufRead + Unpin> AsyncBufRead for Stutter<T> {
+            fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+                let this = self.get_mut();
+                if this.stutter(cx) {
+                    return Poll::Pending;
+                }
+                match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+                    Poll::Ready(Ok(buf)) => Poll::Ready(Ok(&buf[..cmp::min(buf.len(), CHUNK)])),
+                    other => other,
+                }
+            }
+
+            fn consume(mut self: Pin<&mut Self>, amt: usize) {
+                Pin::new(&mut self.inner).consume(amt)
+            }
+        }
+
+        impl<T: AsyncWrite + Unpin> AsyncWrite for Stutter<T> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                let n = cmp::min(buf.len(), CHUNK);
+                Pin::new(&mut self.inner).poll_write(cx, &buf[..n])
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                if self.stutter(cx) {
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.inner).poll_close(cx)
+            }
+        }
+    }
+}
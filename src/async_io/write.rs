@@ -0,0 +1,300 @@
+//! Async writer-based compression/decompression streams
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::PollWrite;
+use codec::{Buffer, Decoder, Encoder, TakeOutput};
+use dictionary::SharedDictionary;
+use raw::{Compress, CompressOp, Decompress};
+use {level_params, CompressParams, DecompressParams};
+
+/// A compression stream which will have uncompressed data written to it and
+/// will write compressed data to an output stream.
+///
+/// The stream must be shut down, with `shutdown` for tokio or `close` for
+/// futures-io, to finish the compressed stream.
+pub struct BrotliEncoder<W> {
+    data: Encoder,
+    obj: W,
+    buf: Buffer,
+}
+
+/// A decompression stream which will have compressed data written to it and
+/// will write uncompressed data to an output stream.
+///
+/// Shutting the stream down, with `shutdown` for tokio or `close` for
+/// futures-io, checks that the compressed stream was complete.
+pub struct BrotliDecoder<W> {
+    data: Decoder,
+    obj: W,
+    buf: Buffer,
+}
+
+/// Writes out all of the coder's output.
+fn poll_dump<Rt, C: TakeOutput, W: PollWrite<Rt> + Unpin>(
+    cx: &mut Context,
+    buf: &mut Buffer,
+    coder: &mut C,
+    obj: &mut W,
+) -> Poll<io::Result<()>> {
+    loop {
+        while !buf.pending().is_empty() {
+            let amt = ready!(Pin::new(&mut *obj).poll_write(cx, buf.pending()))?;
+            buf.advance(amt)?;
+        }
+        if !buf.refill(coder) {
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl<W> BrotliEncoder<W> {
+    /// Create a new compression stream which will compress at the given level
+    /// to write compress output to the give output stream.
+    pub fn new(obj: W, level: u32) -> BrotliEncoder<W> {
+        BrotliEncoder::from_params(obj, &level_params(level))
+    }
+
+    /// Creates a new encoder with a custom `CompressParams`.
+    pub fn from_params(obj: W, params: &CompressParams) -> BrotliEncoder<W> {
+        let mut data = Compress::new();
+        data.set_params(params).unwrap();
+        BrotliEncoder {
+            data: Encoder::new(data),
+            obj,
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.obj
+    }
+
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    ///
+    /// Some of the output may still be buffered rather than written to the
+    /// underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this encoder, returning the underlying writer.
+    ///
+    /// Any output which hasn't been written out yet is lost, so this should
+    /// only be called once the stream has been shut down.
+    pub fn into_inner(self) -> W {
+        self.obj
+    }
+}
+
+impl<W: Unpin> BrotliEncoder<W> {
+    fn poll_write_data<Rt>(&mut self, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>>
+    where
+        W: PollWrite<Rt>,
+    {
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.data.check()?;
+        ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+        Poll::Ready(self.data.write(data))
+    }
+
+    // Flush or finish the compressed stream, writing out all of its output
+    fn poll_op<Rt>(&mut self, cx: &mut Context, op: CompressOp) -> Poll<io::Result<()>>
+    where
+        W: PollWrite<Rt>,
+    {
+        loop {
+            ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+            if self.data.flush(op)? {
+                return poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj);
+            }
+        }
+    }
+
+    fn poll_flush_data<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        W: PollWrite<Rt>,
+    {
+        ready!(self.poll_op(cx, CompressOp::Flush))?;
+        Pin::new(&mut self.obj).poll_flush(cx)
+    }
+
+    fn poll_close_data<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        W: PollWrite<Rt>,
+    {
+        ready!(self.poll_op(cx, CompressOp::Finish))?;
+        Pin::new(&mut self.obj).poll_close(cx)
+    }
+}
+
+impl<W> BrotliDecoder<W> {
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`.
+    pub fn new(obj: W) -> BrotliDecoder<W> {
+        BrotliDecoder::from_params(obj, &DecompressParams::new())
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, using a custom dictionary.
+    ///
+    /// The input must have been compressed with the same dictionary.
+    pub fn with_dictionary(obj: W, dictionary: &[u8]) -> BrotliDecoder<W> {
+        BrotliDecoder::with_data(obj, Decompress::with_dictionary(dictionary))
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, using a shared custom dictionary.
+    pub fn with_shared_dictionary(obj: W, dictionary: &SharedDictionary) -> BrotliDecoder<W> {
+        BrotliDecoder::with_data(obj, Decompress::with_shared_dictionary(dictionary))
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, with custom decompression parameters.
+    pub fn from_params(obj: W, params: &DecompressParams) -> BrotliDecoder<W> {
+        BrotliDecoder::with_data(obj, Decompress::from_params(params))
+    }
+
+    fn with_data(obj: W, data: Decompress) -> BrotliDecoder<W> {
+        BrotliDecoder {
+            data: Decoder::new(data),
+            obj,
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.obj
+    }
+
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    ///
+    /// Some of the output may still be buffered rather than written to the
+    /// underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this decoder, returning the underlying writer.
+    ///
+    /// Any output which hasn't been written out yet is lost, so this should
+    /// only be called once the stream has been shut down.
+    pub fn into_inner(self) -> W {
+        self.obj
+    }
+}
+
+impl<W: Unpin> BrotliDecoder<W> {
+    fn poll_write_data<Rt>(&mut self, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>>
+    where
+        W: PollWrite<Rt>,
+    {
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.data.check()?;
+        ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+        Poll::Ready(self.data.write(data))
+    }
+
+    fn poll_flush_data<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        W: PollWrite<Rt>,
+    {
+        ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+        Pin::new(&mut self.obj).poll_flush(cx)
+    }
+
+    fn poll_close_data<Rt>(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        W: PollWrite<Rt>,
+    {
+        loop {
+            ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+            if self.data.finish()? {
+                break;
+            }
+        }
+        ready!(poll_dump(cx, &mut self.buf, &mut self.data, &mut self.obj))?;
+        Pin::new(&mut self.obj).poll_close(cx)
+    }
+}
+
+/// Implements the `AsyncWrite` traits of tokio and futures-io for a stream
+/// with `poll_write_data`, `poll_flush_data` and `poll_close_data`.
+macro_rules! impl_async_write {
+    ($ty:ident) => {
+        #[cfg(feature = "tokio")]
+        impl<W: tokio_crate::io::AsyncWrite + Unpin> tokio_crate::io::AsyncWrite for $ty<W> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                data: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.get_mut().poll_write_data::<super::Tokio>(cx, data)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                self.get_mut().poll_flush_data::<super::Tokio>(cx)
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                self.get_mut().poll_close_data::<super::Tokio>(cx)
+            }
+        }
+
+        #[cfg(feature = "futures-io")]
+        impl<W: futures_io::AsyncWrite + Unpin> futures_io::AsyncWrite for $ty<W> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                data: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.get_mut().poll_write_data::<super::Futures>(cx, data)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                self.get_mut().poll_flush_data::<super::Futures>(cx)
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                self.get_mut().poll_close_data::<super::Futures>(cx)
+            }
+        }
+    };
+}
+
+impl_async_write!(BrotliEncoder);
+impl_async_write!(BrotliDecoder);
@@ -2,16 +2,12 @@
 
 use std::io;
 use std::io::prelude::*;
+use std::mem;
 
-use super::CompressParams;
-use raw::{self, CoStatus, Compress, CompressOp, DeStatus, Decompress};
-
-#[derive(Clone, Copy, Eq, PartialEq)]
-enum DoneStatus {
-    Processing,
-    Finishing,
-    Done,
-}
+use super::{level_params, CompressParams, DecompressParams};
+use codec::{Decoder, Encoder};
+use dictionary::SharedDictionary;
+use raw::{Compress, Decompress};
 
 /// A brotli encoder, or compressor.
 ///
@@ -19,9 +15,7 @@ enum DoneStatus {
 /// data from an underlying stream and emit a stream of compressed data.
 pub struct BrotliEncoder<R: BufRead> {
     obj: R,
-    data: Compress,
-    done: DoneStatus,
-    err: Option<raw::Error>,
+    data: Encoder,
 }
 
 /// A brotli decoder, or decompressor.
@@ -30,8 +24,7 @@ pub struct BrotliEncoder<R: BufRead> {
 /// compressed data as input, providing the decompressed data when read from.
 pub struct BrotliDecoder<R: BufRead> {
     obj: R,
-    data: Decompress,
-    err: Option<raw::Error>,
+    data: Decoder,
 }
 
 impl<R: BufRead> BrotliEncoder<R> {
@@ -41,24 +34,20 @@ impl<R: BufRead> BrotliEncoder<R> {
     /// The `level` argument here is typically 0-11.
     pub fn new(r: R, level: u32) -> BrotliEncoder<R> {
         let mut data = Compress::new();
-        data.set_params(CompressParams::new().quality(level));
+        data.set_params(&level_params(level)).unwrap();
         BrotliEncoder {
             obj: r,
-            data: data,
-            done: DoneStatus::Processing,
-            err: None,
+            data: Encoder::new(data),
         }
     }
 
     /// Creates a new encoder with a custom `CompressParams`.
     pub fn from_params(r: R, params: &CompressParams) -> BrotliEncoder<R> {
         let mut data = Compress::new();
-        data.set_params(params);
+        data.set_params(params).unwrap();
         BrotliEncoder {
             obj: r,
-            data: data,
-            done: DoneStatus::Processing,
-            err: None,
+            data: Encoder::new(data),
         }
     }
 
@@ -75,83 +64,62 @@ impl<R: BufRead> BrotliEncoder<R> {
         &mut self.obj
     }
 
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
     /// Consumes this encoder, returning the underlying reader.
     pub fn into_inner(self) -> R {
         self.obj
     }
+
+    /// Inserts a metadata block into the compressed stream.
+    ///
+    /// The block is emitted by the following reads, after everything
+    /// compressed from the input so far and before any more input is read.
+    /// Metadata is opaque, out-of-band data which is skipped by decoders, so
+    /// it doesn't change the decompressed output. The decoders in this crate
+    /// can't extract it, see `raw::Decompress`.
+    ///
+    /// Blocks longer than `MAX_METADATA_LEN` are rejected with an
+    /// `InvalidInput` error whose source is a `MetadataTooLong`, as are blocks
+    /// inserted once the end of the input has been reached.
+    pub fn write_metadata(&mut self, data: &[u8]) -> io::Result<()> {
+        self.data.queue_metadata(data)
+    }
+
+    /// Resets the state of this encoder entirely, swapping out the input
+    /// stream for another.
+    ///
+    /// The compression parameters are kept. Any data not yet read from the
+    /// current stream is discarded, as are any metadata blocks not yet
+    /// emitted, and the previous input stream is returned.
+    pub fn reset(&mut self, r: R) -> R {
+        self.data.reset();
+        mem::replace(&mut self.obj, r)
+    }
 }
 
 impl<R: BufRead> Read for BrotliEncoder<R> {
-    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
-        // If the compressor has failed at some point, this is set.
-        // Unfortunately we have no idea what status is in the compressor
-        // was in when it failed so we can't do anything except bail again.
-        if let Some(ref err) = self.err {
-            return Err(err.clone().into());
+        if let Some(n) = self.data.read_pending(buf)? {
+            return Ok(n);
         }
-
-        if let Some(data) = self.data.take_output(Some(buf.len())) {
-            buf[..data.len()].copy_from_slice(data);
-            return Ok(data.len());
-        }
-
-        match self.done {
-            DoneStatus::Done => return Ok(0),
-            DoneStatus::Finishing => return tryfinish(self, buf),
-            DoneStatus::Processing => (),
-        }
-
         loop {
-            let amt_in;
-            let amt_out;
-            {
-                let input = &mut self.obj.fill_buf()?;
-                let avail_in = input.len();
-                if avail_in == 0 {
-                    break;
-                }
-                let output = &mut buf;
-                let avail_out = output.len();
-                if let Err(err) = self.data.compress(CompressOp::Process, input, output) {
-                    self.err = Some(err.clone().into());
-                    return Err(err.into());
-                }
-                amt_in = avail_in - input.len();
-                amt_out = avail_out - output.len();
-            }
+            let (amt_in, amt_out) = self.data.read(self.obj.fill_buf()?, buf)?;
             self.obj.consume(amt_in);
-
-            if amt_out == 0 {
-                assert!(amt_in != 0);
-                continue;
+            if let Some(n) = amt_out {
+                return Ok(n);
             }
-            return Ok(amt_out);
-        }
-        self.done = DoneStatus::Finishing;
-        return tryfinish(self, buf);
-
-        fn tryfinish<R: BufRead>(
-            enc: &mut BrotliEncoder<R>,
-            mut buf: &mut [u8],
-        ) -> io::Result<usize> {
-            let output = &mut buf;
-            let avail_out = output.len();
-            let iscomplete = match enc.data.compress(CompressOp::Finish, &mut &[][..], output) {
-                Ok(c) => c,
-                Err(err) => {
-                    enc.err = err.clone().into();
-                    return Err(err.into());
-                }
-            };
-            let written = avail_out - output.len();
-            assert!(written != 0 || iscomplete == CoStatus::Finished);
-            if iscomplete == CoStatus::Finished {
-                enc.done = DoneStatus::Done
-            }
-            Ok(written)
         }
     }
 }
@@ -161,9 +129,37 @@ impl<R: BufRead> BrotliDecoder<R> {
     /// stream.
     pub fn new(r: R) -> BrotliDecoder<R> {
         BrotliDecoder {
-            data: Decompress::new(),
+            data: Decoder::new(Decompress::new()),
+            obj: r,
+        }
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using a custom dictionary.
+    ///
+    /// The stream must have been compressed with the same dictionary.
+    pub fn with_dictionary(r: R, dictionary: &[u8]) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::with_dictionary(dictionary)),
+            obj: r,
+        }
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using a shared custom dictionary.
+    pub fn with_shared_dictionary(r: R, dictionary: &SharedDictionary) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::with_shared_dictionary(dictionary)),
+            obj: r,
+        }
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, with custom decompression parameters.
+    pub fn from_params(r: R, params: &DecompressParams) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::from_params(params)),
             obj: r,
-            err: None,
         }
     }
 
@@ -180,56 +176,44 @@ impl<R: BufRead> BrotliDecoder<R> {
         &mut self.obj
     }
 
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
     /// Consumes this decoder, returning the underlying reader.
     pub fn into_inner(self) -> R {
         self.obj
     }
+
+    /// Resets the state of this decoder entirely, swapping out the input
+    /// stream for another.
+    ///
+    /// The decompression parameters are kept, and the previous input stream
+    /// is returned.
+    pub fn reset(&mut self, r: R) -> R {
+        self.data.reset();
+        mem::replace(&mut self.obj, r)
+    }
 }
 
 impl<R: BufRead> Read for BrotliDecoder<R> {
-    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
-        // If the decompressor has failed at some point, this is set.
-        // Unfortunately we have no idea what status is in the compressor
-        // was in when it failed so we can't do anything except bail again.
-        if let Some(ref err) = self.err {
-            return Err(err.clone().into());
-        }
-
+        self.data.check()?;
         loop {
-            let amt_in;
-            let amt_out;
-            let status;
-            {
-                let mut input = self.obj.fill_buf()?;
-                let avail_in = input.len();
-                let avail_out = buf.len();
-                status = match self.data.decompress(&mut input, &mut buf) {
-                    Ok(s) => s,
-                    Err(err) => {
-                        self.err = Some(err.clone().into());
-                        return Err(err.into());
-                    }
-                };
-                amt_in = avail_in - input.len();
-                amt_out = avail_out - buf.len()
-            }
+            let (amt_in, amt_out) = self.data.read(self.obj.fill_buf()?, buf)?;
             self.obj.consume(amt_in);
-
-            if amt_in == 0 && status == DeStatus::NeedInput {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "corrupted brotli stream",
-                ));
-            }
-            if amt_out == 0 && status != DeStatus::Finished {
-                assert!(amt_in != 0);
-                continue;
+            if let Some(n) = amt_out {
+                return Ok(n);
             }
-
-            return Ok(amt_out);
         }
     }
 }
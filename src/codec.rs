@@ -0,0 +1,421 @@
+//! Encoder and decoder state machines shared by the I/O streams
+//!
+//! The synchronous `bufread` and `write` streams and the async adapters all
+//! drive `raw::Compress` and `raw::Decompress` the same way, and only differ in
+//! how they get at their input and output. The logic lives here, written in
+//! terms of slices, and each stream supplies the I/O around it.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+
+use raw::{self, CoStatus, Compress, CompressOp, DeStatus, Decompress};
+use {MetadataTooLong, MAX_METADATA_LEN};
+
+/// Size of the buffer holding output which hasn't been written out yet.
+pub const BUF_SIZE: usize = 32 * 1024;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum DoneStatus {
+    Processing,
+    Finishing,
+    Done,
+}
+
+/// Compression state, for streams which read uncompressed input and for
+/// streams which have it written to them.
+pub struct Encoder {
+    pub data: Compress,
+    done: DoneStatus,
+    err: Option<raw::Error>,
+    // Metadata blocks queued by reading streams, and how much of the first
+    // one has been emitted
+    metadata: VecDeque<Vec<u8>>,
+    metadata_pos: usize,
+}
+
+/// Decompression state, for streams which read compressed input and for
+/// streams which have it written to them.
+pub struct Decoder {
+    pub data: Decompress,
+    err: Option<raw::Error>,
+}
+
+/// Compressed or decompressed output taken from a coder which hasn't been
+/// written out yet.
+///
+/// TODO: if we could peek, the buffer wouldn't be necessary
+pub struct Buffer {
+    buf: Vec<u8>,
+    cur: usize,
+}
+
+/// A coder which buffers its output internally.
+pub trait TakeOutput {
+    fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]>;
+}
+
+impl Encoder {
+    pub fn new(data: Compress) -> Encoder {
+        Encoder {
+            data,
+            done: DoneStatus::Processing,
+            err: None,
+            metadata: VecDeque::new(),
+            metadata_pos: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.data.reset();
+        self.done = DoneStatus::Processing;
+        self.err = None;
+        self.metadata.clear();
+        self.metadata_pos = 0;
+    }
+
+    /// Checks the compressor is still usable before doing any I/O.
+    pub fn check(&self) -> io::Result<()> {
+        // If the compressor has failed at some point, this is set.
+        // Unfortunately we have no idea what status is in the compressor
+        // was in when it failed so we can't do anything except bail again.
+        match self.err {
+            Some(ref err) => Err(err.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    fn compress(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> io::Result<CoStatus> {
+        self.data.compress(op, input, output).map_err(|err| {
+            self.err = Some(err.clone());
+            err.into()
+        })
+    }
+
+    /// Reads compressed output into `buf` without needing any more input.
+    ///
+    /// Returns `None` if input is needed to make progress, in which case
+    /// `read` should be called with it.
+    pub fn read_pending(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.check()?;
+
+        if let Some(data) = self.data.take_output(Some(buf.len())) {
+            buf[..data.len()].copy_from_slice(data);
+            return Ok(Some(data.len()));
+        }
+
+        while let Some(block) = self.metadata.pop_front() {
+            let mut input = &block[self.metadata_pos..];
+            let mut out = &mut *buf;
+            let (avail_in, avail_out) = (input.len(), out.len());
+            let emitted = self.emit_metadata(&mut input, &mut out)?;
+            let written = avail_out - out.len();
+            self.metadata_pos += avail_in - input.len();
+            if emitted {
+                self.metadata_pos = 0;
+            } else {
+                self.metadata.push_front(block);
+            }
+            if written != 0 {
+                return Ok(Some(written));
+            }
+        }
+
+        match self.done {
+            DoneStatus::Done => Ok(Some(0)),
+            DoneStatus::Finishing => self.finish(buf).map(Some),
+            DoneStatus::Processing => Ok(None),
+        }
+    }
+
+    /// Compresses `input` into `buf`, where empty input marks the end of the
+    /// stream.
+    ///
+    /// Returns the amount of input consumed, and the amount of output
+    /// produced, or `None` if the input was consumed without producing any
+    /// output yet.
+    pub fn read(
+        &mut self,
+        mut input: &[u8],
+        mut buf: &mut [u8],
+    ) -> io::Result<(usize, Option<usize>)> {
+        let avail_in = input.len();
+        if avail_in == 0 {
+            self.done = DoneStatus::Finishing;
+            return self.finish(buf).map(|n| (0, Some(n)));
+        }
+        let avail_out = buf.len();
+        self.compress(CompressOp::Process, &mut input, &mut buf)?;
+        let amt_in = avail_in - input.len();
+        let amt_out = avail_out - buf.len();
+        if amt_out == 0 {
+            assert!(amt_in != 0);
+            return Ok((amt_in, None));
+        }
+        Ok((amt_in, Some(amt_out)))
+    }
+
+    fn finish(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+        let avail_out = buf.len();
+        let status = self.compress(CompressOp::Finish, &mut &[][..], &mut buf)?;
+        let written = avail_out - buf.len();
+        assert!(written != 0 || status == CoStatus::Finished);
+        if status == CoStatus::Finished {
+            self.done = DoneStatus::Done
+        }
+        Ok(written)
+    }
+
+    /// Compresses as much of `data` as possible into the compressor's
+    /// internal buffer, returning how much of it was consumed.
+    ///
+    /// `data` must not be empty, and `check` should be called and any output
+    /// written out first, to keep the buffer small.
+    pub fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+        // Zero-length output buf to keep it all inside the compressor buffer
+        let avail_in = data.len();
+        self.compress(CompressOp::Process, &mut data, &mut &mut [][..])?;
+        assert!(avail_in != data.len());
+        Ok(avail_in - data.len())
+    }
+
+    /// Checks that `data` can be emitted as a metadata block.
+    pub fn check_metadata(&self, data: &[u8]) -> io::Result<()> {
+        self.check()?;
+        if data.len() > MAX_METADATA_LEN {
+            return Err(MetadataTooLong { len: data.len() }.into());
+        }
+        if self.done != DoneStatus::Processing {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "brotli metadata can't be emitted after the end of the stream",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queues a metadata block to be emitted by `read_pending`, before any
+    /// more input is compressed.
+    pub fn queue_metadata(&mut self, data: &[u8]) -> io::Result<()> {
+        self.check_metadata(data)?;
+        self.metadata.push_back(data.to_vec());
+        Ok(())
+    }
+
+    /// Emits a metadata block into `buf`, returning whether it is complete
+    /// once the output has been taken.
+    ///
+    /// Until then this must be called again with the rest of `data`, after
+    /// taking any output from the compressor. All input compressed so far is
+    /// flushed ahead of the block.
+    pub fn emit_metadata(&mut self, data: &mut &[u8], buf: &mut &mut [u8]) -> io::Result<bool> {
+        let status = self.compress(CompressOp::EmitMetadata, data, buf)?;
+        Ok(status == CoStatus::Finished)
+    }
+
+    /// Runs a flush or finish operation into the compressor's internal
+    /// buffer, returning whether it is complete once the output has been
+    /// written out.
+    pub fn flush(&mut self, op: CompressOp) -> io::Result<bool> {
+        self.check()?;
+        let status = self.compress(op, &mut &[][..], &mut &mut [][..])?;
+        Ok(status == CoStatus::Finished)
+    }
+}
+
+impl Decoder {
+    pub fn new(data: Decompress) -> Decoder {
+        Decoder { data, err: None }
+    }
+
+    pub fn reset(&mut self) {
+        self.data.reset();
+        self.err = None;
+    }
+
+    /// Checks the decompressor is still usable before doing any I/O.
+    pub fn check(&self) -> io::Result<()> {
+        // If the decompressor has failed at some point, this is set.
+        // Unfortunately we have no idea what status is in the decompressor
+        // was in when it failed so we can't do anything except bail again.
+        match self.err {
+            Some(ref err) => Err(err.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    fn decompress(&mut self, input: &mut &[u8], output: &mut &mut [u8]) -> io::Result<DeStatus> {
+        self.data.decompress(input, output).map_err(|err| {
+            self.err = Some(err.clone());
+            err.into()
+        })
+    }
+
+    /// Decompresses `input` into `buf`, where empty input marks the end of the
+    /// stream.
+    ///
+    /// Returns the amount of input consumed, and the amount of output
+    /// produced, or `None` if the input was consumed without producing any
+    /// output yet.
+    pub fn read(
+        &mut self,
+        mut input: &[u8],
+        mut buf: &mut [u8],
+    ) -> io::Result<(usize, Option<usize>)> {
+        let avail_in = input.len();
+        let avail_out = buf.len();
+        let status = self.decompress(&mut input, &mut buf)?;
+        let amt_in = avail_in - input.len();
+        let amt_out = avail_out - buf.len();
+
+        if amt_in == 0 && status == DeStatus::NeedInput {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "brotli compressed stream is truncated",
+            ));
+        }
+        if amt_out == 0 && status != DeStatus::Finished {
+            assert!(amt_in != 0);
+            return Ok((amt_in, None));
+        }
+        Ok((amt_in, Some(amt_out)))
+    }
+
+    /// Decompresses as much of `data` as possible into the decompressor's
+    /// internal buffer, returning how much of it was consumed.
+    ///
+    /// `data` must not be empty, and `check` should be called and any output
+    /// written out first, to keep the buffer small.
+    pub fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+        // Zero-length output buf to keep it all inside the decompressor buffer
+        let avail_in = data.len();
+        let status = self.decompress(&mut data, &mut &mut [][..])?;
+        assert!(avail_in != data.len() || status == DeStatus::Finished);
+        Ok(avail_in - data.len())
+    }
+
+    /// Runs the decompressor without any more input, returning whether the
+    /// stream is complete once the output has been written out.
+    pub fn finish(&mut self) -> io::Result<bool> {
+        self.check()?;
+        match self.decompress(&mut &[][..], &mut &mut [][..])? {
+            DeStatus::Finished => Ok(true),
+            // When decoding a truncated file, brotli returns DeStatus::NeedInput.
+            // Since we're finishing, we cannot provide more data so this is an
+            // error.
+            DeStatus::NeedInput => {
+                let msg = "brotli compressed stream is truncated or otherwise corrupt";
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg))
+            }
+            DeStatus::NeedOutput => Ok(false),
+        }
+    }
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            buf: Vec::with_capacity(BUF_SIZE),
+            cur: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.cur = 0;
+    }
+
+    /// Returns the output which hasn't been written out yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[self.cur..]
+    }
+
+    /// Marks `amt` bytes of the pending output as written out.
+    ///
+    /// Writing nothing at all is an error, as the writer will never accept
+    /// the rest of the output.
+    pub fn advance(&mut self, amt: usize) -> io::Result<()> {
+        if amt == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write buffered data",
+            ));
+        }
+        self.cur += amt;
+        if self.cur == self.buf.len() {
+            self.clear();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the buffer is full, and must be written out before
+    /// any more output is added.
+    pub fn is_full(&self) -> bool {
+        self.buf.len() >= BUF_SIZE
+    }
+
+    /// Runs `f` with the unused space of the buffer, keeping whatever it
+    /// writes there as pending output.
+    pub fn fill<T, F>(&mut self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut &mut [u8]) -> io::Result<T>,
+    {
+        let len = self.buf.len();
+        self.buf.resize(BUF_SIZE.max(len), 0);
+        let mut out = &mut self.buf[len..];
+        let avail_out = out.len();
+        let res = f(&mut out);
+        let written = avail_out - out.len();
+        self.buf.truncate(len + written);
+        res
+    }
+
+    /// Refills the buffer from the coder's output once it has all been
+    /// written out, returning false if there is no more output.
+    pub fn refill<C: TakeOutput>(&mut self, coder: &mut C) -> bool {
+        assert!(self.pending().is_empty());
+        match coder.take_output(Some(BUF_SIZE)) {
+            Some(data) => {
+                self.buf.extend_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes out the pending output, leaving any in the coder alone.
+    pub fn write_pending<W: Write>(&mut self, obj: &mut W) -> io::Result<()> {
+        while !self.pending().is_empty() {
+            let amt = obj.write(self.pending())?;
+            self.advance(amt)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out all of the coder's output.
+    pub fn dump<C: TakeOutput, W: Write>(&mut self, coder: &mut C, obj: &mut W) -> io::Result<()> {
+        loop {
+            self.write_pending(obj)?;
+            if !self.refill(coder) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl TakeOutput for Encoder {
+    fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        self.data.take_output(size_limit)
+    }
+}
+
+impl TakeOutput for Decoder {
+    fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        self.data.take_output(size_limit)
+    }
+}
@@ -0,0 +1,216 @@
+//! Dictionary-compressed brotli ("dcb") framing
+//!
+//! The `dcb` content encoding from HTTP compression dictionary transport is a
+//! brotli stream compressed against a custom dictionary, preceded by a fixed
+//! header: the magic bytes `ff 44 43 42` followed by the SHA-256 hash of the
+//! dictionary. The hash lets the receiver pick the right dictionary, or fail
+//! cleanly when it doesn't have it.
+//!
+//! The dictionary is loaded as a prefix of the sliding window, so streams are
+//! only interchangeable with other `dcb` implementations while the dictionary
+//! and the data decoded so far fit within the window.
+//!
+//! This module is only available with the `dcb` feature enabled.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use dictionary::SharedDictionary;
+
+pub mod read;
+pub mod write;
+
+/// The magic bytes at the start of every `dcb` stream.
+pub const MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+
+/// Length of the `dcb` header: the magic bytes plus the dictionary hash.
+pub const HEADER_LEN: usize = 36;
+
+/// The SHA-256 hash identifying a dictionary.
+pub type DictionaryHash = [u8; 32];
+
+/// A dictionary along with its SHA-256 hash.
+///
+/// The hash is computed once when the dictionary is created, and cloning only
+/// bumps a reference count.
+#[derive(Clone, Debug)]
+pub struct Dictionary {
+    dictionary: SharedDictionary,
+    hash: DictionaryHash,
+}
+
+/// A source of dictionaries for `dcb` decoders, keyed by their hash.
+pub trait DictionaryLookup {
+    /// Returns the dictionary with the given SHA-256 hash, if it is known.
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary>;
+}
+
+/// A collection of dictionaries which decoders can pick from by hash.
+#[derive(Clone, Debug, Default)]
+pub struct DictionarySet {
+    dictionaries: HashMap<DictionaryHash, Dictionary>,
+}
+
+/// Errors specific to reading the `dcb` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The stream does not start with the `dcb` magic bytes.
+    InvalidMagic,
+    /// The stream was compressed with a dictionary that the lookup does not
+    /// know about.
+    UnknownDictionary(DictionaryHash),
+}
+
+impl Dictionary {
+    /// Creates a new dictionary, hashing its contents.
+    pub fn new(dictionary: SharedDictionary) -> Dictionary {
+        let hash = Sha256::digest(dictionary.as_bytes()).into();
+        Dictionary { dictionary, hash }
+    }
+
+    /// Returns the SHA-256 hash of this dictionary.
+    pub fn hash(&self) -> &DictionaryHash {
+        &self.hash
+    }
+
+    /// Returns the dictionary itself.
+    pub fn dictionary(&self) -> &SharedDictionary {
+        &self.dictionary
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(&MAGIC);
+        header[MAGIC.len()..].copy_from_slice(&self.hash);
+        header
+    }
+}
+
+impl<'a> From<&'a [u8]> for Dictionary {
+    fn from(data: &'a [u8]) -> Dictionary {
+        Dictionary::new(SharedDictionary::new(data))
+    }
+}
+
+impl DictionarySet {
+    /// Creates a new, empty, set of dictionaries.
+    pub fn new() -> DictionarySet {
+        DictionarySet::default()
+    }
+
+    /// Adds a dictionary to this set.
+    pub fn insert(&mut self, dictionary: Dictionary) -> &mut DictionarySet {
+        self.dictionaries.insert(dictionary.hash, dictionary);
+        self
+    }
+}
+
+impl DictionaryLookup for DictionarySet {
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary> {
+        self.dictionaries.get(hash).cloned()
+    }
+}
+
+impl DictionaryLookup for Dictionary {
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary> {
+        if *hash == self.hash {
+            Some(self.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl<F> DictionaryLookup for F
+where
+    F: Fn(&DictionaryHash) -> Option<Dictionary>,
+{
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary> {
+        self(hash)
+    }
+}
+
+impl DictionaryLookup for &DictionarySet {
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary> {
+        (**self).lookup(hash)
+    }
+}
+
+impl DictionaryLookup for &Dictionary {
+    fn lookup(&self, hash: &DictionaryHash) -> Option<Dictionary> {
+        (**self).lookup(hash)
+    }
+}
+
+/// Validates a complete header and finds the dictionary it refers to.
+fn parse_header<L: DictionaryLookup>(
+    header: &[u8; HEADER_LEN],
+    lookup: &L,
+) -> Result<Dictionary, Error> {
+    if header[..MAGIC.len()] != MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&header[MAGIC.len()..]);
+    lookup.lookup(&hash).ok_or(Error::UnknownDictionary(hash))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidMagic => f.write_str("not a dictionary-compressed brotli stream"),
+            Error::UnknownDictionary(ref hash) => {
+                f.write_str("unknown brotli dictionary with hash ")?;
+                for b in hash.iter() {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dictionary_hash() {
+        let dict = Dictionary::from(&b"abc"[..]);
+        assert_eq!(
+            &dict.hash()[..4],
+            &[0xba, 0x78, 0x16, 0xbf],
+            "SHA-256 of \"abc\""
+        );
+        let header = dict.header();
+        assert_eq!(&header[..4], &MAGIC);
+        assert_eq!(parse_header(&header, &dict).unwrap().hash(), dict.hash());
+
+        let other = Dictionary::from(&b"abd"[..]);
+        assert_eq!(
+            parse_header(&header, &other).unwrap_err(),
+            Error::UnknownDictionary(*dict.hash())
+        );
+        let mut set = DictionarySet::new();
+        set.insert(other).insert(dict.clone());
+        assert!(parse_header(&header, &set).is_ok());
+        let lookup = |hash: &DictionaryHash| set.lookup(hash);
+        assert!(parse_header(&header, &lookup).is_ok());
+
+        let mut bad = header;
+        bad[0] = 0;
+        assert_eq!(parse_header(&bad, &set).unwrap_err(), Error::InvalidMagic);
+    }
+}
@@ -0,0 +1,241 @@
+//! Reader-based `dcb` encoding/decoding streams
+
+use std::io;
+use std::io::prelude::*;
+
+use super::{parse_header, Dictionary, DictionaryLookup, HEADER_LEN};
+use read::{BrotliDecoder, BrotliEncoder};
+use {level_params, CompressParams};
+
+/// A `dcb` compression stream which wraps an uncompressed stream of data. The
+/// `dcb` header followed by compressed data will be read from the stream.
+pub struct DcbEncoder<R: Read> {
+    header: [u8; HEADER_LEN],
+    pos: usize,
+    inner: BrotliEncoder<R>,
+}
+
+/// A `dcb` decompression stream which wraps a `dcb` encoded stream of data.
+/// Decompressed data will be read from the stream.
+///
+/// The header is read on the first call to `read`, and the dictionary is then
+/// picked from the lookup by its hash.
+pub struct DcbDecoder<R: Read, L: DictionaryLookup> {
+    obj: Option<R>,
+    inner: Option<BrotliDecoder<R>>,
+    header: [u8; HEADER_LEN],
+    pos: usize,
+    lookup: L,
+}
+
+impl<R: Read> DcbEncoder<R> {
+    /// Create a new compression stream which will compress at the given level
+    /// against `dictionary`.
+    pub fn new(r: R, dictionary: &Dictionary, level: u32) -> DcbEncoder<R> {
+        DcbEncoder::from_params(r, dictionary, &level_params(level))
+    }
+
+    /// Create a new compression stream with custom compression parameters.
+    ///
+    /// Any dictionary set in `params` is replaced by `dictionary`.
+    pub fn from_params(r: R, dictionary: &Dictionary, params: &CompressParams) -> DcbEncoder<R> {
+        let mut params = params.clone();
+        params.shared_dictionary(dictionary.dictionary());
+        DcbEncoder {
+            header: dictionary.header(),
+            pos: 0,
+            inner: BrotliEncoder::from_params(r, &params),
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this encoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    /// Consumes this encoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: Read> Read for DcbEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < HEADER_LEN {
+            let n = (&self.header[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read, L: DictionaryLookup> DcbDecoder<R, L> {
+    /// Create a new decompression stream, which will read `dcb` encoded data
+    /// from the given input stream, using a dictionary from `lookup`.
+    pub fn new(r: R, lookup: L) -> DcbDecoder<R, L> {
+        DcbDecoder {
+            obj: Some(r),
+            inner: None,
+            header: [0; HEADER_LEN],
+            pos: 0,
+            lookup,
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        match self.inner {
+            Some(ref inner) => inner.get_ref(),
+            None => self.obj.as_ref().unwrap(),
+        }
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        match self.inner {
+            Some(ref mut inner) => inner.get_mut(),
+            None => self.obj.as_mut().unwrap(),
+        }
+    }
+
+    /// Consumes this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            Some(inner) => inner.into_inner(),
+            None => self.obj.unwrap(),
+        }
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let obj = self.obj.as_mut().unwrap();
+        while self.pos < HEADER_LEN {
+            match obj.read(&mut self.header[self.pos..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "dcb header is truncated",
+                    ))
+                }
+                Ok(n) => self.pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let dictionary = parse_header(&self.header, &self.lookup)?;
+        let obj = self.obj.take().unwrap();
+        self.inner = Some(BrotliDecoder::with_shared_dictionary(
+            obj,
+            dictionary.dictionary(),
+        ));
+        Ok(())
+    }
+}
+
+impl<R: Read, L: DictionaryLookup> Read for DcbDecoder<R, L> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inner.is_none() {
+            self.read_header()?;
+        }
+        self.inner.as_mut().unwrap().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DcbDecoder, DcbEncoder};
+    use dcb::{self, Dictionary, DictionarySet};
+    use std::io;
+    use std::io::prelude::*;
+
+    #[test]
+    fn smoke() {
+        let dict = Dictionary::from(&b"{\"user\": \"\", \"admin\": false}"[..]);
+        let m = b"{\"user\": \"brotli\", \"admin\": false}";
+        let mut data = Vec::new();
+        DcbEncoder::new(&m[..], &dict, 9)
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(&data[..4], &dcb::MAGIC);
+        assert_eq!(&data[4..36], dict.hash());
+
+        let mut set = DictionarySet::new();
+        set.insert(Dictionary::from(&b"unrelated"[..]))
+            .insert(dict.clone());
+        let mut d = DcbDecoder::new(&data[..], &set);
+        let mut data2 = Vec::new();
+        d.read_to_end(&mut data2).unwrap();
+        assert_eq!(data2, &m[..]);
+    }
+
+    #[test]
+    fn unknown_dictionary() {
+        let dict = Dictionary::from(&b"dictionary"[..]);
+        let mut data = Vec::new();
+        DcbEncoder::new(&b"some data"[..], &dict, 9)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let mut d = DcbDecoder::new(&data[..], DictionarySet::new());
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.get_ref().unwrap().downcast_ref::<dcb::Error>().unwrap();
+        assert_eq!(*err, dcb::Error::UnknownDictionary(*dict.hash()));
+
+        let mut d = DcbDecoder::new(&data[..10], dict);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    // Interrupts every other read while the header is being read.
+    struct Interrupting<'a> {
+        data: &'a [u8],
+        pos: usize,
+        interrupt: bool,
+    }
+
+    impl<'a> Read for Interrupting<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos < dcb::HEADER_LEN {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+                }
+            }
+            let n = self.data[self.pos..].len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn interrupted_header() {
+        let dict = Dictionary::from(&b"dictionary"[..]);
+        let mut data = Vec::new();
+        DcbEncoder::new(&b"some data"[..], &dict, 9)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let r = Interrupting {
+            data: &data,
+            pos: 0,
+            interrupt: false,
+        };
+        let mut d = DcbDecoder::new(r, &dict);
+        let mut buf = [0; 1];
+        assert_eq!(d.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b's');
+    }
+}
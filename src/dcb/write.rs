@@ -0,0 +1,255 @@
+//! Writer-based `dcb` encoding/decoding streams
+
+use std::io;
+use std::io::prelude::*;
+
+use super::{parse_header, Dictionary, DictionaryLookup, HEADER_LEN};
+use write::{BrotliDecoder, BrotliEncoder};
+use {level_params, CompressParams};
+
+/// A `dcb` compression stream which will have uncompressed data written to it
+/// and will write the `dcb` header and compressed data to an output stream.
+pub struct DcbEncoder<W: Write> {
+    inner: BrotliEncoder<HeaderWriter<W>>,
+}
+
+/// A `dcb` decompression stream which will have `dcb` encoded data written to
+/// it and will write uncompressed data to an output stream.
+///
+/// The dictionary is picked from the lookup by its hash once the header has
+/// been written.
+pub struct DcbDecoder<W: Write, L: DictionaryLookup> {
+    obj: Option<W>,
+    inner: Option<BrotliDecoder<W>>,
+    header: [u8; HEADER_LEN],
+    pos: usize,
+    lookup: L,
+}
+
+/// Writes the `dcb` header before the first compressed byte.
+struct HeaderWriter<W: Write> {
+    header: [u8; HEADER_LEN],
+    pos: usize,
+    obj: W,
+}
+
+impl<W: Write> HeaderWriter<W> {
+    fn write_header(&mut self) -> io::Result<()> {
+        while self.pos < HEADER_LEN {
+            match self.obj.write(&self.header[self.pos..])? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write dcb header",
+                    ))
+                }
+                n => self.pos += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HeaderWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        self.obj.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.obj.flush()
+    }
+}
+
+impl<W: Write> DcbEncoder<W> {
+    /// Create a new compression stream which will compress at the given level
+    /// against `dictionary`.
+    pub fn new(obj: W, dictionary: &Dictionary, level: u32) -> DcbEncoder<W> {
+        DcbEncoder::from_params(obj, dictionary, &level_params(level))
+    }
+
+    /// Create a new compression stream with custom compression parameters.
+    ///
+    /// Any dictionary set in `params` is replaced by `dictionary`.
+    pub fn from_params(obj: W, dictionary: &Dictionary, params: &CompressParams) -> DcbEncoder<W> {
+        let mut params = params.clone();
+        params.shared_dictionary(dictionary.dictionary());
+        let obj = HeaderWriter {
+            header: dictionary.header(),
+            pos: 0,
+            obj,
+        };
+        DcbEncoder {
+            inner: BrotliEncoder::from_params(obj, &params),
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner.get_ref().obj
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner.get_mut().obj
+    }
+
+    /// Consumes this encoder, flushing the output stream.
+    ///
+    /// This will flush the underlying data stream and then return the contained
+    /// writer if the flush succeeded.
+    pub fn finish(self) -> io::Result<W> {
+        self.inner.finish().map(|w| w.obj)
+    }
+}
+
+impl<W: Write> Write for DcbEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write, L: DictionaryLookup> DcbDecoder<W, L> {
+    /// Creates a new decoding stream which will decode all `dcb` encoded input
+    /// written to it into `obj`, using a dictionary from `lookup`.
+    pub fn new(obj: W, lookup: L) -> DcbDecoder<W, L> {
+        DcbDecoder {
+            obj: Some(obj),
+            inner: None,
+            header: [0; HEADER_LEN],
+            pos: 0,
+            lookup,
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        match self.inner {
+            Some(ref inner) => inner.get_ref(),
+            None => self.obj.as_ref().unwrap(),
+        }
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        match self.inner {
+            Some(ref mut inner) => inner.get_mut(),
+            None => self.obj.as_mut().unwrap(),
+        }
+    }
+
+    /// Unwrap the underlying writer, finishing the decompression stream.
+    pub fn finish(&mut self) -> io::Result<W> {
+        match self.inner {
+            Some(ref mut inner) => inner.finish(),
+            None if self.pos == HEADER_LEN => {
+                Err(parse_header(&self.header, &self.lookup).unwrap_err().into())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "dcb header is truncated",
+            )),
+        }
+    }
+}
+
+impl<W: Write, L: DictionaryLookup> Write for DcbDecoder<W, L> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut inner) = self.inner {
+            return inner.write(data);
+        }
+        // Once the whole header has been seen the only way to get here is a
+        // header which failed to parse, so keep reporting that error.
+        let n = (&mut self.header[self.pos..]).write(data)?;
+        self.pos += n;
+        if self.pos == HEADER_LEN {
+            let dictionary = parse_header(&self.header, &self.lookup)?;
+            let obj = self.obj.take().unwrap();
+            self.inner = Some(BrotliDecoder::with_shared_dictionary(
+                obj,
+                dictionary.dictionary(),
+            ));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Some(ref mut inner) => inner.flush(),
+            None => self.obj.as_mut().unwrap().flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DcbDecoder, DcbEncoder};
+    use dcb::{self, Dictionary};
+    use std::io;
+    use std::io::prelude::*;
+
+    #[test]
+    fn smoke() {
+        let dict = Dictionary::from(&b"<p class=\"comment\"></p>"[..]);
+        let d = DcbDecoder::new(Vec::new(), dict.clone());
+        let mut c = DcbEncoder::new(d, &dict, 9);
+        for i in 0..100 {
+            write!(c, "<p class=\"comment\">{}</p>", i).unwrap();
+        }
+        let data = c.finish().unwrap().finish().unwrap();
+        let expected = (0..100)
+            .map(|i| format!("<p class=\"comment\">{}</p>", i))
+            .collect::<String>();
+        assert_eq!(data, expected.as_bytes());
+    }
+
+    #[test]
+    fn header() {
+        let dict = Dictionary::from(&b"dictionary"[..]);
+        let data = DcbEncoder::new(Vec::new(), &dict, 9).finish().unwrap();
+        assert_eq!(&data[..4], &dcb::MAGIC);
+        assert_eq!(&data[4..36], dict.hash());
+
+        // Feed the header a byte at a time.
+        let mut d = DcbDecoder::new(Vec::new(), &dict);
+        for b in data.iter() {
+            d.write_all(&[*b]).unwrap();
+        }
+        assert_eq!(d.finish().unwrap(), b"");
+
+        let other = Dictionary::from(&b"other dictionary"[..]);
+        let mut d = DcbDecoder::new(Vec::new(), &other);
+        let err = d.write_all(&data).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<dcb::Error>().unwrap();
+        assert_eq!(*err, dcb::Error::UnknownDictionary(*dict.hash()));
+
+        // The error sticks instead of the rest of the stream being swallowed.
+        for _ in 0..2 {
+            let err = d.write(&data[dcb::HEADER_LEN..]).unwrap_err();
+            let err = err.get_ref().unwrap().downcast_ref::<dcb::Error>().unwrap();
+            assert_eq!(*err, dcb::Error::UnknownDictionary(*dict.hash()));
+        }
+        let err = d.finish().unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<dcb::Error>().unwrap();
+        assert_eq!(*err, dcb::Error::UnknownDictionary(*dict.hash()));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let dict = Dictionary::from(&b"dictionary"[..]);
+        let mut d = DcbDecoder::new(Vec::new(), &dict);
+        d.write_all(&dcb::MAGIC).unwrap();
+        assert_eq!(d.finish().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,344 @@
+//! Custom dictionaries shared between many compressors and decompressors
+//!
+//! A `SharedDictionary` holds the dictionary bytes behind a reference count,
+//! so a single dictionary can be attached to any number of `raw::Compress` and
+//! `raw::Decompress` instances, across threads, without copying it.
+//!
+//! Only the bytes are shared: each compressor still hashes the dictionary
+//! itself, see `SharedDictionary`.
+//!
+//! Dictionaries can be built from a corpus of representative samples with
+//! `train`, and `evaluate` reports how much a dictionary helps on a set of
+//! samples.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use brotli_sys;
+
+use raw::{self, compress_buf};
+use CompressParams;
+
+/// A custom dictionary whose bytes can be cheaply shared between compressors
+/// and decompressors.
+///
+/// Cloning a `SharedDictionary` only bumps a reference count, and attaching it
+/// doesn't copy the bytes. It is not a prepared dictionary, though: every
+/// `raw::Compress` it is attached to still passes it to
+/// `BrotliEncoderSetCustomDictionary` on its first call to `compress`, which
+/// copies the dictionary into that compressor's ring buffer and inserts every
+/// position of it into the hash table. That work is proportional to the
+/// dictionary's length and is repeated for each compressor. Decompressors only
+/// reference the bytes.
+#[derive(Clone)]
+pub struct SharedDictionary {
+    data: Arc<[u8]>,
+}
+
+impl SharedDictionary {
+    /// Creates a new dictionary from a copy of `data`.
+    pub fn new(data: &[u8]) -> SharedDictionary {
+        SharedDictionary { data: data.into() }
+    }
+
+    /// Returns the length of the dictionary, in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns whether the dictionary is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the contents of the dictionary.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns whether both handles point to the same dictionary, as opposed
+    /// to two dictionaries which merely have the same bytes.
+    ///
+    /// Unlike `==`, this never compares the bytes.
+    pub fn ptr_eq(this: &SharedDictionary, other: &SharedDictionary) -> bool {
+        Arc::ptr_eq(&this.data, &other.data)
+    }
+}
+
+impl From<Vec<u8>> for SharedDictionary {
+    fn from(data: Vec<u8>) -> SharedDictionary {
+        SharedDictionary { data: data.into() }
+    }
+}
+
+impl<'a> From<&'a [u8]> for SharedDictionary {
+    fn from(data: &'a [u8]) -> SharedDictionary {
+        SharedDictionary::new(data)
+    }
+}
+
+impl AsRef<[u8]> for SharedDictionary {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl PartialEq for SharedDictionary {
+    fn eq(&self, other: &SharedDictionary) -> bool {
+        Arc::ptr_eq(&self.data, &other.data) || self.data == other.data
+    }
+}
+
+impl Eq for SharedDictionary {}
+
+impl fmt::Debug for SharedDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedDictionary({} bytes)", self.len())
+    }
+}
+
+/// Length of the substrings used to score candidate segments.
+const DMER_LEN: usize = 8;
+/// Length of the segments the dictionary is assembled from.
+const SEGMENT_LEN: usize = 64;
+
+/// Builds a dictionary of at most `target_size` bytes for data resembling
+/// `samples`.
+///
+/// The samples are scanned for substrings which occur in many of them, and
+/// the segments containing the most such substrings are concatenated, with
+/// the most useful segments at the end of the dictionary where references to
+/// them are cheapest. This is the same approach as zstd's COVER trainer.
+///
+/// The result may be shorter than `target_size` if the samples don't have
+/// enough content in common. Samples shorter than 8 bytes are ignored.
+pub fn train(samples: &[&[u8]], target_size: usize) -> Vec<u8> {
+    // Count how many samples each substring appears in, rather than how often
+    // it appears, so a single repetitive sample doesn't dominate.
+    let mut freqs = HashMap::new();
+    for sample in samples {
+        let dmers = sample.windows(DMER_LEN).collect::<HashSet<_>>();
+        for dmer in dmers {
+            *freqs.entry(dmer).or_insert(0u32) += 1;
+        }
+    }
+    freqs.retain(|_, n| *n > 1);
+
+    // Split the corpus into one epoch per segment we'd like to pick, and pick
+    // the best segment from each epoch. Substrings which are covered by a
+    // picked segment don't count towards any later segment.
+    let positions = samples
+        .iter()
+        .enumerate()
+        .filter(|&(_, s)| s.len() >= DMER_LEN)
+        .flat_map(|(i, s)| (0..s.len() - DMER_LEN + 1).map(move |j| (i, j)))
+        .collect::<Vec<_>>();
+    let epochs = (target_size / SEGMENT_LEN).max(1);
+    let epoch_len = positions.len() / epochs + 1;
+
+    let mut segments = Vec::new();
+    let mut size = 0;
+    while size < target_size {
+        let before = size;
+        for epoch in positions.chunks(epoch_len) {
+            if size >= target_size {
+                break;
+            }
+            if let Some(segment) = best_segment(samples, epoch, &freqs) {
+                for dmer in segment.windows(DMER_LEN) {
+                    freqs.remove(dmer);
+                }
+                let segment = &segment[..segment.len().min(target_size - size)];
+                size += segment.len();
+                segments.push(segment);
+            }
+        }
+        if size == before {
+            break;
+        }
+    }
+
+    let mut dictionary = Vec::with_capacity(size);
+    for segment in segments.iter().rev() {
+        dictionary.extend_from_slice(segment);
+    }
+    dictionary
+}
+
+/// Finds the segment starting in `epoch` with the highest score, if any
+/// segment scores at all.
+fn best_segment<'a>(
+    samples: &[&'a [u8]],
+    epoch: &[(usize, usize)],
+    freqs: &HashMap<&[u8], u32>,
+) -> Option<&'a [u8]> {
+    let freq = |sample: &[u8], i: usize| {
+        sample
+            .get(i..i + DMER_LEN)
+            .and_then(|dmer| freqs.get(dmer))
+            .map_or(0, |&n| u64::from(n))
+    };
+    // Substrings running off the end of the sample score nothing, so segments
+    // near the end of a sample are naturally penalised.
+    let dmers = SEGMENT_LEN - DMER_LEN + 1;
+    let mut best = None;
+    let mut best_score = 0;
+    let mut window = None;
+    for &(i, j) in epoch {
+        let sample = samples[i];
+        // Slide the window along the sample, starting afresh at the start of
+        // each sample in the epoch.
+        let score = match window {
+            Some((wi, wj, score)) if wi == i && wj + 1 == j => {
+                score - freq(sample, wj) + freq(sample, j + dmers - 1)
+            }
+            _ => (j..j + dmers).map(|k| freq(sample, k)).sum(),
+        };
+        window = Some((i, j, score));
+        if score > best_score {
+            best_score = score;
+            best = Some(&sample[j..(j + SEGMENT_LEN).min(sample.len())]);
+        }
+    }
+    best
+}
+
+/// The result of compressing a set of samples with and without a dictionary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Evaluation {
+    /// Total size of the samples.
+    pub uncompressed: usize,
+    /// Total size of the samples compressed without the dictionary.
+    pub compressed: usize,
+    /// Total size of the samples compressed with the dictionary.
+    pub compressed_with_dictionary: usize,
+}
+
+impl Evaluation {
+    /// Returns the compression ratio without the dictionary.
+    pub fn ratio(&self) -> f64 {
+        self.uncompressed as f64 / self.compressed as f64
+    }
+
+    /// Returns the compression ratio with the dictionary.
+    pub fn ratio_with_dictionary(&self) -> f64 {
+        self.uncompressed as f64 / self.compressed_with_dictionary as f64
+    }
+}
+
+/// Compresses each of `samples` individually with `compress_buf`, both with
+/// and without `dictionary`, and reports the total sizes.
+///
+/// Any dictionary already set in `params` is ignored.
+pub fn evaluate(
+    dictionary: &[u8],
+    samples: &[&[u8]],
+    params: &CompressParams,
+) -> Result<Evaluation, raw::Error> {
+    let mut plain = params.clone();
+    plain.dictionary = None;
+    let mut with_dictionary = params.clone();
+    with_dictionary.dictionary(dictionary);
+
+    let mut buf = Vec::new();
+    let mut evaluation = Evaluation {
+        uncompressed: 0,
+        compressed: 0,
+        compressed_with_dictionary: 0,
+    };
+    for sample in samples {
+        let bound = unsafe { brotli_sys::BrotliEncoderMaxCompressedSize(sample.len()) };
+        buf.resize(bound.max(sample.len() + 1024), 0);
+        evaluation.uncompressed += sample.len();
+        evaluation.compressed += compress_buf(&plain, sample, &mut &mut buf[..])?;
+        evaluation.compressed_with_dictionary +=
+            compress_buf(&with_dictionary, sample, &mut &mut buf[..])?;
+    }
+    Ok(evaluation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, train, SharedDictionary};
+    use raw::{CoStatus, Compress, CompressOp, DeStatus, Decompress};
+    use std::thread;
+    use CompressParams;
+
+    #[test]
+    fn shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedDictionary>();
+
+        let dict = SharedDictionary::from(b"<html><head><title></title></head>".to_vec());
+        let threads = (0..4)
+            .map(|i| {
+                let dict = dict.clone();
+                thread::spawn(move || {
+                    let input = format!("<html><head><title>{}</title></head>", i);
+                    let mut c = Compress::with_shared_dictionary(&dict);
+                    let mut data = [0; 128];
+                    let n = {
+                        let mut out = &mut data[..];
+                        assert_eq!(
+                            c.compress(CompressOp::Finish, &mut input.as_bytes(), &mut out),
+                            Ok(CoStatus::Finished)
+                        );
+                        128 - out.len()
+                    };
+
+                    let mut d = Decompress::with_shared_dictionary(&dict);
+                    let mut dst = [0; 128];
+                    assert_eq!(
+                        d.decompress(&mut &data[..n], &mut &mut dst[..]),
+                        Ok(DeStatus::Finished)
+                    );
+                    assert_eq!(&dst[..input.len()], input.as_bytes());
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn train_and_evaluate() {
+        let samples = (0..200)
+            .map(|i| {
+                format!(
+                    "{{\"id\": {}, \"type\": \"user\", \"attributes\": {{\"name\": \"user{}\", \
+                     \"email\": \"user{}@example.com\", \"verified\": {}}}, \"links\": \
+                     {{\"self\": \"https://api.example.com/v1/users/{}\"}}}}",
+                    i,
+                    i * 7,
+                    i * 13,
+                    i % 2 == 0,
+                    i
+                )
+            })
+            .collect::<Vec<_>>();
+        let samples = samples.iter().map(|s| s.as_bytes()).collect::<Vec<_>>();
+
+        let dictionary = train(&samples, 1024);
+        assert!(!dictionary.is_empty());
+        assert!(dictionary.len() <= 1024);
+
+        let mut params = CompressParams::new();
+        params.quality(9).unwrap();
+        let evaluation = evaluate(&dictionary, &samples, &params).unwrap();
+        assert_eq!(
+            evaluation.uncompressed,
+            samples.iter().map(|s| s.len()).sum::<usize>()
+        );
+        assert!(evaluation.compressed_with_dictionary < evaluation.compressed);
+        assert!(evaluation.ratio_with_dictionary() > evaluation.ratio());
+    }
+
+    #[test]
+    fn train_without_common_content() {
+        assert!(train(&[], 1024).is_empty());
+        assert!(train(&[b"short", b"samples"], 1024).is_empty());
+        assert!(train(&[b"nothing in common", b"between these two"], 1024).is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+//! Async encoders and decoders for the `futures-io` traits
+//!
+//! These work with any runtime built on `futures-io`, such as async-std and
+//! smol, and otherwise behave like the streams in the `tokio` module. The
+//! `bufread` streams read from an `AsyncBufRead` and implement `AsyncRead`
+//! and `AsyncBufRead`, and the `write` streams implement `AsyncWrite` and
+//! write to an underlying `AsyncWrite`.
+//!
+//! Flushing a `write::BrotliEncoder` flushes the compressor, so everything
+//! written so far can be decompressed by the other end, and closing it
+//! finishes the compressed stream. Nothing can be done when these streams are
+//! dropped, so `close` must be called to complete the output.
+//!
+//! These are the same types as in the `tokio` module, and implement that
+//! module's traits as well when the `tokio` feature is enabled.
+//!
+//! This module is only available with the `futures-io` feature enabled.
+
+pub mod bufread;
+pub mod write;
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_executor::block_on;
+    use futures_util::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use super::{bufread, write};
+    use async_io::tests::{lines, random_data, Stutter};
+    use raw::{DeStatus, Decompress};
+
+    #[test]
+    fn bufread_roundtrip() {
+        let data = random_data();
+        let e = bufread::BrotliEncoder::new(Stutter::new(&data[..]), 6);
+        let mut d = bufread::BrotliDecoder::new(Stutter::new(BufReader::new(e)));
+        let mut result = Vec::new();
+        block_on(d.read_to_end(&mut result)).unwrap();
+        assert!(result == data);
+
+        assert_eq!(d.total_out(), data.len() as u64);
+        let e = d.into_inner().inner.into_inner();
+        assert_eq!(e.total_in(), data.len() as u64);
+    }
+
+    #[test]
+    fn bufread_lines() {
+        let text = lines();
+        // The streams are buffered readers themselves, so they can be chained
+        // without a `BufReader`.
+        let e = bufread::BrotliEncoder::new(Stutter::new(text.as_bytes()), 6);
+        let mut d = bufread::BrotliDecoder::new(Stutter::new(e));
+        let mut line = String::new();
+        block_on(d.read_line(&mut line)).unwrap();
+        assert_eq!(line, "line 0\n");
+        let mut rest = String::new();
+        block_on(d.read_to_string(&mut rest)).unwrap();
+        assert_eq!(line + &rest, text);
+    }
+
+    #[test]
+    fn bufread_truncated() {
+        let mut compressed = Vec::new();
+        let mut e = bufread::BrotliEncoder::new(&b"truncated stream"[..], 6);
+        block_on(e.read_to_end(&mut compressed)).unwrap();
+
+        let mut d = bufread::BrotliDecoder::new(&compressed[..compressed.len() - 1]);
+        let err = block_on(d.read_to_end(&mut Vec::new())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_roundtrip() {
+        let data = random_data();
+        let d = write::BrotliDecoder::new(Stutter::new(Vec::new()));
+        let mut e = write::BrotliEncoder::new(Stutter::new(d), 6);
+        for chunk in data.chunks(1000) {
+            block_on(e.write_all(chunk)).unwrap();
+        }
+        block_on(e.close()).unwrap();
+        assert_eq!(e.total_in(), data.len() as u64);
+
+        let d = e.into_inner().inner;
+        assert_eq!(d.total_out(), data.len() as u64);
+        assert!(d.into_inner().inner == data);
+    }
+
+    #[test]
+    fn write_flush() {
+        let mut e = write::BrotliEncoder::new(Stutter::new(Vec::new()), 6);
+        block_on(e.write_all(b"hello, ")).unwrap();
+        block_on(e.flush()).unwrap();
+
+        // Everything written so far can be decompressed, but the stream
+        // isn't complete yet.
+        let mut d = Decompress::new();
+        let mut out = [0; 64];
+        let status = d.decompress(&mut &e.get_ref().inner[..], &mut &mut out[..]);
+        assert_eq!(status, Ok(DeStatus::NeedInput));
+        assert_eq!(&out[..7], b"hello, ");
+
+        block_on(e.write_all(b"world")).unwrap();
+        block_on(e.close()).unwrap();
+        let mut d = write::BrotliDecoder::new(Vec::new());
+        block_on(d.write_all(&e.into_inner().inner)).unwrap();
+        block_on(d.close()).unwrap();
+        assert_eq!(d.get_ref(), b"hello, world");
+    }
+
+    #[test]
+    fn write_truncated() {
+        let mut e = write::BrotliEncoder::new(Vec::new(), 6);
+        block_on(e.write_all(b"truncated stream")).unwrap();
+        block_on(e.close()).unwrap();
+        let compressed = e.into_inner();
+
+        let mut d = write::BrotliDecoder::new(Vec::new());
+        block_on(d.write_all(&compressed[..compressed.len() - 1])).unwrap();
+        let err = block_on(d.close()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
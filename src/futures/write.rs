@@ -0,0 +1,3 @@
+//! Async writer-based compression/decompression streams
+
+pub use async_io::write::{BrotliDecoder, BrotliEncoder};
@@ -0,0 +1,437 @@
+//! Pools of compressors and decompressors shared between threads
+//!
+//! A `CompressorPool` hands out `raw::Compress` instances configured with the
+//! requested `CompressParams`, reusing an idle instance with the same
+//! parameters when there is one. Instances are reset and returned to the pool
+//! when the handle is dropped, up to a maximum number of idle instances.
+//! `DecompressorPool` does the same for `raw::Decompress`.
+//!
+//! Custom dictionaries are matched by identity: an idle instance is only
+//! reused for parameters holding the same `SharedDictionary`, or a clone of
+//! it, so the dictionary bytes are never compared. Parameters set up with
+//! `dictionary(&[u8])` get a new dictionary each time and should share a
+//! `SharedDictionary` instead.
+//!
+//! Resetting a returned instance re-creates its libbrotli state (see
+//! `Compress::reset`), so that is skipped when there is no room in the pool
+//! for it. A compressor also loads its dictionary again on its first
+//! `compress` after a reset. What pooling saves is allocating the Rust side
+//! of each instance and copying its parameters and dictionary handle.
+
+use std::mem;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use dictionary::SharedDictionary;
+use raw::{CoStatus, Compress, CompressOp, DeStatus, Decompress, Error};
+use {CompressParams, DecompressParams};
+
+/// Usage statistics of a pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of requests served by an idle instance.
+    pub hits: u64,
+    /// Number of requests which needed a new instance.
+    pub misses: u64,
+    /// Number of instances currently idle in the pool.
+    pub idle: usize,
+}
+
+/// A thread-safe pool of compressors.
+pub struct CompressorPool {
+    shelf: Shelf<Key<CompressParams>, Compress>,
+}
+
+/// A compressor checked out of a `CompressorPool`.
+///
+/// This dereferences to `raw::Compress` for inspecting it, and returns the
+/// compressor to the pool when dropped. It goes back under the parameters it
+/// was checked out with, so they can't be changed in the meantime:
+///
+/// ```compile_fail
+/// use brotli2::pool::CompressorPool;
+/// use brotli2::{CompressParams, Preset};
+///
+/// let pool = CompressorPool::new(1);
+/// let mut c = pool.get(&CompressParams::preset(Preset::Realtime));
+/// c.set_params(&CompressParams::preset(Preset::HttpStatic));
+/// ```
+pub struct PooledCompress<'a> {
+    pool: &'a CompressorPool,
+    key: Key<CompressParams>,
+    data: Option<Compress>,
+}
+
+/// A thread-safe pool of decompressors.
+pub struct DecompressorPool {
+    shelf: Shelf<Key<DecompressParams>, Decompress>,
+}
+
+/// A decompressor checked out of a `DecompressorPool`.
+///
+/// This dereferences to `raw::Decompress` for inspecting it, and returns the
+/// decompressor to the pool when dropped, under the parameters it was checked
+/// out with.
+pub struct PooledDecompress<'a> {
+    pool: &'a DecompressorPool,
+    key: Key<DecompressParams>,
+    data: Option<Decompress>,
+}
+
+/// The parameters an instance was created with, with the dictionary split out
+/// so that it is compared by identity.
+#[derive(Default)]
+struct Key<P> {
+    params: P,
+    dictionary: Option<SharedDictionary>,
+}
+
+impl<P: PartialEq> PartialEq for Key<P> {
+    fn eq(&self, other: &Key<P>) -> bool {
+        let same_dictionary = match (&self.dictionary, &other.dictionary) {
+            (Some(a), Some(b)) => SharedDictionary::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        same_dictionary && self.params == other.params
+    }
+}
+
+impl<'a> From<&'a CompressParams> for Key<CompressParams> {
+    fn from(params: &'a CompressParams) -> Key<CompressParams> {
+        let mut params = params.clone();
+        let dictionary = params.dictionary.take();
+        Key { params, dictionary }
+    }
+}
+
+impl<'a> From<&'a DecompressParams> for Key<DecompressParams> {
+    fn from(params: &'a DecompressParams) -> Key<DecompressParams> {
+        let mut params = params.clone();
+        let dictionary = params.dictionary.take();
+        Key { params, dictionary }
+    }
+}
+
+/// Idle instances along with the parameters they were created with.
+struct Shelf<P, T> {
+    idle: Mutex<Vec<(P, T)>>,
+    max_idle: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<P: PartialEq, T> Shelf<P, T> {
+    fn new(max_idle: usize) -> Shelf<P, T> {
+        Shelf {
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn take(&self, params: &P) -> Option<T> {
+        let mut idle = self.idle.lock().unwrap();
+        match idle.iter().rposition(|(p, _)| p == params) {
+            Some(i) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(idle.swap_remove(i).1)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Returns `data` to the shelf if there is room for it, calling `reset`
+    /// on it first.
+    ///
+    /// Resetting allocates a new libbrotli state, so it is skipped when the
+    /// instance would be dropped anyway, and isn't done under the lock.
+    fn put<F: FnOnce(&mut T)>(&self, params: P, mut data: T, reset: F) {
+        if self.idle.lock().unwrap().len() >= self.max_idle {
+            return;
+        }
+        reset(&mut data);
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push((params, data));
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap().len(),
+        }
+    }
+}
+
+impl CompressorPool {
+    /// Creates a new, empty, pool which keeps at most `max_idle` compressors
+    /// around when they aren't in use.
+    pub fn new(max_idle: usize) -> CompressorPool {
+        CompressorPool {
+            shelf: Shelf::new(max_idle),
+        }
+    }
+
+    /// Checks out a compressor configured with `params`, creating one if
+    /// there is no idle compressor with the same parameters.
+    pub fn get(&self, params: &CompressParams) -> PooledCompress<'_> {
+        let key = Key::from(params);
+        let data = self.shelf.take(&key).unwrap_or_else(|| {
+            let mut data = Compress::new();
+            // Parameters are always accepted by a compressor which hasn't
+            // started yet.
+            data.set_params(params).unwrap();
+            data
+        });
+        PooledCompress {
+            pool: self,
+            key,
+            data: Some(data),
+        }
+    }
+
+    /// Returns the usage statistics of this pool.
+    pub fn stats(&self) -> PoolStats {
+        self.shelf.stats()
+    }
+}
+
+impl<'a> Deref for PooledCompress<'a> {
+    type Target = Compress;
+
+    fn deref(&self) -> &Compress {
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl<'a> PooledCompress<'a> {
+    /// Compresses some input data, see `raw::Compress::compress`.
+    pub fn compress(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Result<CoStatus, Error> {
+        self.data.as_mut().unwrap().compress(op, input, output)
+    }
+
+    /// Takes compressed output from the internal buffer, see
+    /// `raw::Compress::take_output`.
+    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        self.data.as_mut().unwrap().take_output(size_limit)
+    }
+}
+
+impl<'a> Drop for PooledCompress<'a> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            let key = mem::take(&mut self.key);
+            self.pool.shelf.put(key, data, |data| data.reset());
+        }
+    }
+}
+
+impl DecompressorPool {
+    /// Creates a new, empty, pool which keeps at most `max_idle`
+    /// decompressors around when they aren't in use.
+    pub fn new(max_idle: usize) -> DecompressorPool {
+        DecompressorPool {
+            shelf: Shelf::new(max_idle),
+        }
+    }
+
+    /// Checks out a decompressor configured with `params`, creating one if
+    /// there is no idle decompressor with the same parameters.
+    pub fn get(&self, params: &DecompressParams) -> PooledDecompress<'_> {
+        let key = Key::from(params);
+        let data = self
+            .shelf
+            .take(&key)
+            .unwrap_or_else(|| Decompress::from_params(params));
+        PooledDecompress {
+            pool: self,
+            key,
+            data: Some(data),
+        }
+    }
+
+    /// Returns the usage statistics of this pool.
+    pub fn stats(&self) -> PoolStats {
+        self.shelf.stats()
+    }
+}
+
+impl<'a> Deref for PooledDecompress<'a> {
+    type Target = Decompress;
+
+    fn deref(&self) -> &Decompress {
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl<'a> PooledDecompress<'a> {
+    /// Decompresses some input data, see `raw::Decompress::decompress`.
+    pub fn decompress(
+        &mut self,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Result<DeStatus, Error> {
+        self.data.as_mut().unwrap().decompress(input, output)
+    }
+
+    /// Takes decompressed output from the internal buffer, see
+    /// `raw::Decompress::take_output`.
+    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        self.data.as_mut().unwrap().take_output(size_limit)
+    }
+}
+
+impl<'a> Drop for PooledDecompress<'a> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            let key = mem::take(&mut self.key);
+            self.pool.shelf.put(key, data, |data| data.reset());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressorPool, DecompressorPool, PoolStats};
+    use dictionary::SharedDictionary;
+    use raw::{CoStatus, CompressOp, DeStatus};
+    use std::sync::Arc;
+    use std::thread;
+    use {CompressParams, Preset};
+
+    #[test]
+    fn hits_and_misses() {
+        let pool = CompressorPool::new(1);
+        let fast = CompressParams::preset(Preset::Realtime);
+        let slow = CompressParams::preset(Preset::HttpStatic);
+        {
+            let _a = pool.get(&fast);
+            let _b = pool.get(&fast);
+        }
+        // Only one of the two compressors is kept.
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 0,
+                misses: 2,
+                idle: 1
+            }
+        );
+        drop(pool.get(&fast));
+        drop(pool.get(&slow));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 3,
+                idle: 1
+            }
+        );
+    }
+
+    #[test]
+    fn dictionary_identity() {
+        let pool = CompressorPool::new(2);
+        let dictionary = SharedDictionary::new(b"shared dictionary");
+        let mut params = CompressParams::new();
+        params.shared_dictionary(&dictionary);
+        drop(pool.get(&params));
+        // A clone of the same dictionary matches, a copy of its bytes doesn't.
+        drop(pool.get(&params.clone()));
+        let mut copy = CompressParams::new();
+        copy.dictionary(b"shared dictionary");
+        drop(pool.get(&copy));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 2,
+                idle: 2
+            }
+        );
+    }
+
+    #[test]
+    fn reuse_keeps_params() {
+        fn compress(pool: &CompressorPool, params: &CompressParams, input: &[u8]) -> Vec<u8> {
+            let mut c = pool.get(params);
+            let mut data = vec![0; 4096];
+            let n = {
+                let mut out = &mut data[..];
+                let status = c.compress(CompressOp::Finish, &mut &input[..], &mut out);
+                assert_eq!(status, Ok(CoStatus::Finished));
+                4096 - out.len()
+            };
+            data.truncate(n);
+            data
+        }
+
+        let input = "a reused compressor matches a new one ".repeat(50);
+        let pool = CompressorPool::new(1);
+        let mut params = CompressParams::preset(Preset::Realtime);
+        params.lgwin(16).unwrap();
+        let first = compress(&pool, &params, input.as_bytes());
+        let second = compress(&pool, &params, input.as_bytes());
+        assert_eq!(pool.stats().hits, 1);
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            compress(&CompressorPool::new(0), &params, input.as_bytes())
+        );
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let compressors = Arc::new(CompressorPool::new(4));
+        let decompressors = Arc::new(DecompressorPool::new(4));
+        let threads = (0..4)
+            .map(|i| {
+                let compressors = compressors.clone();
+                let decompressors = decompressors.clone();
+                thread::spawn(move || {
+                    let params = CompressParams::preset(Preset::HttpDynamic);
+                    for j in 0..10 {
+                        let input = format!("response {} from thread {}", j, i);
+                        let mut data = [0; 128];
+                        let n = {
+                            let mut c = compressors.get(&params);
+                            let mut out = &mut data[..];
+                            assert_eq!(
+                                c.compress(CompressOp::Finish, &mut input.as_bytes(), &mut out),
+                                Ok(CoStatus::Finished)
+                            );
+                            128 - out.len()
+                        };
+                        let mut d = decompressors.get(&Default::default());
+                        let mut dst = [0; 128];
+                        assert_eq!(
+                            d.decompress(&mut &data[..n], &mut &mut dst[..]),
+                            Ok(DeStatus::Finished)
+                        );
+                        assert_eq!(&dst[..input.len()], input.as_bytes());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let stats = compressors.stats();
+        assert_eq!(stats.hits + stats.misses, 40);
+        assert!(stats.misses <= 4);
+        assert!(stats.idle <= 4);
+    }
+}
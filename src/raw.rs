@@ -1,23 +1,50 @@
 //! Raw interface to in-memory compression/decompression streams
 
+use std::alloc::{self, Layout, System};
 use std::error;
+use std::ffi::CStr;
 use std::fmt;
 use std::io;
 use std::mem;
 use std::ptr;
 use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use brotli_sys;
-use libc::c_int;
+use libc::{c_int, c_void, size_t};
 
-use super::CompressParams;
+use super::{CompressParams, DecompressParams, ParamsError};
+use allocator::{Allocator, GlobalAllocator};
+use dictionary::SharedDictionary;
 
 /// In-memory state for decompressing brotli-encoded data.
 ///
 /// This stream is at the heart of the I/O streams and is used to decompress an
 /// incoming brotli stream.
+///
+/// Metadata blocks in the stream are skipped over, and their contents can't
+/// be retrieved, see the [limitations](crate#limitations).
 pub struct Decompress {
     state: *mut brotli_sys::BrotliDecoderState,
+    total_in: u64,
+    total_out: u64,
+    // The first two bytes of the stream, to tell a large-window stream apart
+    // from other invalid window sizes. libbrotli may fail before consuming
+    // the second one, so they are recorded as soon as they are passed in.
+    header: [u8; 2],
+    header_len: usize,
+    // Kept so the state can be recreated on `reset`. libbrotli also keeps a
+    // pointer to the custom dictionary rather than copying it, so it has to
+    // live as long as the state does.
+    params: DecompressParams,
+    allocator: Option<Arc<dyn Allocator>>,
+    // The allocator libbrotli actually calls, which counts the memory in use
+    // and enforces the memory limit if there is one. It is only kept alive
+    // here, libbrotli has a pointer to it.
+    _hooks: Option<Box<Arc<dyn Allocator>>>,
+    memory: Option<Arc<MemoryLimit>>,
 }
 
 unsafe impl Send for Decompress {}
@@ -29,6 +56,18 @@ unsafe impl Sync for Decompress {}
 /// data.
 pub struct Compress {
     state: *mut brotli_sys::BrotliEncoderState,
+    total_in: u64,
+    total_out: u64,
+    // Kept so the state can be recreated on `reset`. The custom dictionary is
+    // only loaded on the first call to `compress`, as libbrotli won't accept
+    // any more parameters once it has been loaded.
+    params: CompressParams,
+    dictionary_loaded: bool,
+    allocator: Option<Arc<dyn Allocator>>,
+    // The allocator libbrotli actually calls, which counts the memory in use.
+    // It is only kept alive here, libbrotli has a pointer to it.
+    _hooks: Option<Box<Arc<dyn Allocator>>>,
+    memory: Option<Arc<MemoryLimit>>,
 }
 
 unsafe impl Send for Compress {}
@@ -54,13 +93,191 @@ pub enum CompressOp {
     Finish = brotli_sys::BROTLI_OPERATION_FINISH as isize,
     /// Emit a metadata block to the stream, an opaque piece of out-of-band
     /// data that does not interfere with the main stream of data. Metadata
-    /// blocks *must* be no longer than 16MiB
+    /// blocks *must* be no longer than 16MiB, `MAX_METADATA_LEN`
     EmitMetadata = brotli_sys::BROTLI_OPERATION_EMIT_METADATA as isize,
 }
 
 /// Error that can happen from decompressing or compressing a brotli stream.
 #[derive(Debug, Clone, PartialEq)]
-pub struct Error(());
+pub struct Error {
+    kind: Option<DecodeErrorKind>,
+    offset: Option<(u64, u64)>,
+    limit: Option<LimitExceeded>,
+    large_window: bool,
+}
+
+/// A limit configured in `DecompressParams` which a stream exceeded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LimitExceeded {
+    /// Decoding needed more memory than `DecompressParams::memory_limit`
+    /// allows.
+    Memory,
+    /// The stream decompresses to more than
+    /// `DecompressParams::max_output_size` bytes.
+    OutputSize,
+    /// The stream expands by more than `DecompressParams::max_ratio`.
+    Ratio,
+}
+
+/// The reason libbrotli gave for failing to decode a stream.
+///
+/// These correspond to the `BROTLI_DECODER_ERROR_*` codes returned by
+/// `BrotliDecoderGetErrorCode`. The `Format*` variants indicate that the input
+/// is not a valid brotli stream, while the `Alloc*` variants indicate that the
+/// decoder failed to allocate memory. Codes these bindings don't know about
+/// are reported as `Unknown`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DecodeErrorKind {
+    /// Invalid stream: a nibble of a meta-block length is superfluous.
+    FormatExuberantNibble,
+    /// Invalid stream: a reserved bit is set.
+    FormatReserved,
+    /// Invalid stream: a nibble of a metadata length is superfluous.
+    FormatExuberantMetaNibble,
+    /// Invalid stream: a simple Huffman code has an out-of-range symbol.
+    FormatSimpleHuffmanAlphabet,
+    /// Invalid stream: a simple Huffman code has duplicate symbols.
+    FormatSimpleHuffmanSame,
+    /// Invalid stream: the code length code is incomplete or oversubscribed.
+    FormatClSpace,
+    /// Invalid stream: a Huffman code is incomplete or oversubscribed.
+    FormatHuffmanSpace,
+    /// Invalid stream: a context map repeat code overflows the map.
+    FormatContextMapRepeat,
+    /// Invalid stream: a block length is out of range.
+    FormatBlockLength1,
+    /// Invalid stream: a block length is out of range.
+    FormatBlockLength2,
+    /// Invalid stream: a dictionary reference uses an invalid transform.
+    FormatTransform,
+    /// Invalid stream: a dictionary reference is out of range.
+    FormatDictionary,
+    /// Invalid stream: the window size in the stream header is invalid.
+    ///
+    /// Streams using the large-window brotli extension fail with this error,
+    /// see `Error::is_large_window`.
+    FormatWindowBits,
+    /// Invalid stream: padding bits are not zero.
+    FormatPadding1,
+    /// Invalid stream: padding bits are not zero.
+    FormatPadding2,
+    /// The decoder was called with invalid arguments.
+    InvalidArguments,
+    /// Failed to allocate memory for context modes.
+    AllocContextModes,
+    /// Failed to allocate memory for Huffman tree groups.
+    AllocTreeGroups,
+    /// Failed to allocate memory for a context map.
+    AllocContextMap,
+    /// Failed to allocate memory for the ring buffer.
+    AllocRingBuffer1,
+    /// Failed to allocate memory for the ring buffer.
+    AllocRingBuffer2,
+    /// Failed to allocate memory for block type trees.
+    AllocBlockTypeTrees,
+    /// The decoder reached a state which should be impossible.
+    Unreachable,
+    /// An error code which these bindings don't recognize, such as one added
+    /// by a newer libbrotli.
+    Unknown(i32),
+}
+
+/// The `BROTLI_DECODER_ERROR_*` code for each known `DecodeErrorKind`.
+const DECODE_ERROR_CODES: [(brotli_sys::BrotliDecoderErrorCode, DecodeErrorKind); 23] = {
+    use self::DecodeErrorKind::*;
+
+    [
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE,
+            FormatExuberantNibble,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_RESERVED,
+            FormatReserved,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_META_NIBBLE,
+            FormatExuberantMetaNibble,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_ALPHABET,
+            FormatSimpleHuffmanAlphabet,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_SAME,
+            FormatSimpleHuffmanSame,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_CL_SPACE,
+            FormatClSpace,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_HUFFMAN_SPACE,
+            FormatHuffmanSpace,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_CONTEXT_MAP_REPEAT,
+            FormatContextMapRepeat,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_1,
+            FormatBlockLength1,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_2,
+            FormatBlockLength2,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_TRANSFORM,
+            FormatTransform,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_DICTIONARY,
+            FormatDictionary,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS,
+            FormatWindowBits,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_PADDING_1,
+            FormatPadding1,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_PADDING_2,
+            FormatPadding2,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_INVALID_ARGUMENTS,
+            InvalidArguments,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES,
+            AllocContextModes,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS,
+            AllocTreeGroups,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP,
+            AllocContextMap,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1,
+            AllocRingBuffer1,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2,
+            AllocRingBuffer2,
+        ),
+        (
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES,
+            AllocBlockTypeTrees,
+        ),
+        (brotli_sys::BROTLI_DECODER_ERROR_UNREACHABLE, Unreachable),
+    ]
+};
 
 /// Indication of whether a compression operation is 'complete'. This does
 /// not indicate whether the whole stream is complete - see `Compress::compress`
@@ -89,11 +306,86 @@ impl Decompress {
     /// Creates a new brotli decompression/decoding stream ready to receive
     /// data.
     pub fn new() -> Decompress {
-        unsafe {
-            let state = brotli_sys::BrotliDecoderCreateInstance(None, None, 0 as *mut _);
-            assert!(!state.is_null());
-            Decompress { state: state }
+        Decompress::create(&DecompressParams::new(), None)
+    }
+
+    /// Creates a new decompression stream which decodes data that was
+    /// compressed with the given custom dictionary.
+    ///
+    /// The dictionary is copied and kept alive by this decompressor.
+    pub fn with_dictionary(dictionary: &[u8]) -> Decompress {
+        Decompress::with_shared_dictionary(&SharedDictionary::new(dictionary))
+    }
+
+    /// Creates a new decompression stream with a shared custom dictionary
+    /// attached.
+    ///
+    /// The dictionary is referenced, not copied, and is kept alive by this
+    /// decompressor.
+    pub fn with_shared_dictionary(dictionary: &SharedDictionary) -> Decompress {
+        Decompress::from_params(DecompressParams::new().shared_dictionary(dictionary))
+    }
+
+    /// Creates a new decompression stream configured with `params`.
+    pub fn from_params(params: &DecompressParams) -> Decompress {
+        Decompress::create(params, None)
+    }
+
+    /// Creates a new decompression stream configured with `params`, which
+    /// allocates all of its memory from `allocator`.
+    pub fn with_allocator(params: &DecompressParams, allocator: Arc<dyn Allocator>) -> Decompress {
+        Decompress::create(params, Some(allocator))
+    }
+
+    fn create(params: &DecompressParams, allocator: Option<Arc<dyn Allocator>>) -> Decompress {
+        let memory = if params.memory_limit.is_some() || allocator.is_some() {
+            let inner = allocator
+                .clone()
+                .unwrap_or_else(|| Arc::new(GlobalAllocator(System)));
+            Some(Arc::new(MemoryLimit::new(inner)))
+        } else {
+            None
+        };
+        let hooks = memory
+            .clone()
+            .map(|memory| Box::new(memory as Arc<dyn Allocator>));
+        let (alloc_func, free_func, opaque) = alloc_hooks(&hooks, brotli_alloc);
+        let state =
+            unsafe { brotli_sys::BrotliDecoderCreateInstance(alloc_func, free_func, opaque) };
+        assert!(!state.is_null());
+        if let (Some(memory), Some(limit)) = (memory.as_ref(), params.memory_limit) {
+            memory.limit.store(limit, Ordering::SeqCst);
+        }
+        let d = Decompress {
+            state,
+            total_in: 0,
+            total_out: 0,
+            header: [0; 2],
+            header_len: 0,
+            params: params.clone(),
+            allocator,
+            _hooks: hooks,
+            memory,
+        };
+        if let Some(ref dictionary) = params.dictionary {
+            // Only the last 16MiB can be referenced by a stream, and libbrotli
+            // ignores larger dictionaries entirely.
+            let bytes = dictionary.as_bytes();
+            let bytes = &bytes[bytes.len().saturating_sub(MAX_DICTIONARY_SIZE)..];
+            unsafe {
+                brotli_sys::BrotliDecoderSetCustomDictionary(d.state, bytes.len(), bytes.as_ptr());
+            }
         }
+        d
+    }
+
+    /// Re-creates this decompressor with the parameters it was created with,
+    /// so that it can decode a new stream.
+    ///
+    /// This frees the underlying decoder state and allocates a new one, which
+    /// costs as much as creating a new `Decompress`.
+    pub fn reset(&mut self) {
+        *self = Decompress::create(&self.params, self.allocator.clone());
     }
 
     /// Decompress some input data and write it to a buffer of output data.
@@ -110,16 +402,34 @@ impl Decompress {
     /// # Errors
     ///
     /// If the input stream is not a valid brotli stream, then an error is
-    /// returned.
+    /// returned. The error records how many bytes of compressed input had been
+    /// consumed and how many bytes of output had been produced when decoding
+    /// failed. Decoding also fails once the stream exceeds one of the limits
+    /// set in `DecompressParams`, see `Error::limit_exceeded`.
     pub fn decompress(
         &mut self,
         input: &mut &[u8],
         output: &mut &mut [u8],
     ) -> Result<DeStatus, Error> {
+        while self.header_len < self.header.len() {
+            // `input` always continues the stream from `total_in`
+            match input.get(self.header_len - self.total_in as usize) {
+                Some(&b) => self.header[self.header_len] = b,
+                None => break,
+            }
+            self.header_len += 1;
+        }
         let mut available_in = input.len();
         let mut next_in = input.as_ptr();
-        let mut available_out = output.len();
+        // Never hand out more output than the limits allow
+        let mut available_out = match self.output_allowance(input.len()) {
+            Some((allowance, _)) => output.len().min(allowance.min(usize::MAX as u64) as usize),
+            None => output.len(),
+        };
+        let avail_out = available_out;
         let mut next_out = output.as_mut_ptr();
+        // libbrotli only updates `total_out` when it writes output
+        let mut total_out = self.total_out as usize;
         let r = unsafe {
             brotli_sys::BrotliDecoderDecompressStream(
                 self.state,
@@ -127,13 +437,15 @@ impl Decompress {
                 &mut next_in,
                 &mut available_out,
                 &mut next_out,
-                ptr::null_mut(),
+                &mut total_out,
             )
         };
+        self.total_in += (input.len() - available_in) as u64;
+        self.total_out = total_out as u64;
         *input = &input[input.len() - available_in..];
-        let out_len = output.len();
-        *output = &mut mem::replace(output, &mut [])[out_len - available_out..];
-        Decompress::rc(r)
+        *output = &mut mem::take(output)[avail_out - available_out..];
+        let status = self.rc(r)?;
+        self.check_limits(status)
     }
 
     /// Retrieve a slice of the internal decompressor buffer up to `size_limit` in length
@@ -141,6 +453,13 @@ impl Decompress {
     /// contiguous, consecutive calls may return more output until this function returns
     /// `None`.
     pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        let size_limit = match self.output_allowance(0) {
+            Some((allowance, _)) => {
+                let allowance = allowance.min(usize::MAX as u64) as usize;
+                Some(size_limit.map_or(allowance, |n| n.min(allowance)))
+            }
+            None => size_limit,
+        };
         if let Some(0) = size_limit {
             return None;
         }
@@ -152,16 +471,102 @@ impl Decompress {
                 None
             } else {
                 assert!(!ptr.is_null());
+                self.total_out += size_limit as u64;
                 Some(slice::from_raw_parts(ptr, size_limit))
             }
         }
     }
 
-    fn rc(rc: brotli_sys::BrotliDecoderResult) -> Result<DeStatus, Error> {
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Returns how many bytes of memory this decompressor currently has
+    /// allocated, including a small header per allocation.
+    ///
+    /// Memory is only counted when the decompressor was created with a custom
+    /// allocator or a memory limit, otherwise this returns `None`.
+    pub fn memory_usage(&self) -> Option<usize> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.used.load(Ordering::SeqCst))
+    }
+
+    /// Returns how much more output the limits in the parameters allow if
+    /// `input_len` more bytes of input are consumed, along with the limit
+    /// which is closest.
+    fn output_allowance(&self, input_len: usize) -> Option<(u64, LimitExceeded)> {
+        let mut allowance = self.params.max_output_size.map(|max| {
+            (
+                max.saturating_sub(self.total_out),
+                LimitExceeded::OutputSize,
+            )
+        });
+        if let Some(ratio) = self.params.max_ratio {
+            let total_in = self.total_in + input_len as u64;
+            let n = ratio
+                .saturating_mul(total_in)
+                .saturating_sub(self.total_out);
+            match allowance {
+                Some((a, _)) if a <= n => {}
+                _ => allowance = Some((n, LimitExceeded::Ratio)),
+            }
+        }
+        allowance
+    }
+
+    /// Fails if the stream has produced, or is holding back, more output than
+    /// the limits allow.
+    fn check_limits(&self, status: DeStatus) -> Result<DeStatus, Error> {
+        let exceeded = match self.output_allowance(0) {
+            Some((0, limit))
+                if status == DeStatus::NeedOutput
+                    && unsafe { brotli_sys::BrotliDecoderHasMoreOutput(self.state) } != 0 =>
+            {
+                Some(limit)
+            }
+            _ => None,
+        };
+        // Less input may have been consumed than was allowed for.
+        let exceeded = exceeded.or(match self.params.max_ratio {
+            Some(ratio) if self.total_out > ratio.saturating_mul(self.total_in) => {
+                Some(LimitExceeded::Ratio)
+            }
+            _ => None,
+        });
+        match exceeded {
+            Some(limit) => Err(Error {
+                kind: None,
+                offset: Some((self.total_in, self.total_out)),
+                limit: Some(limit),
+                large_window: false,
+            }),
+            None => Ok(status),
+        }
+    }
+
+    fn rc(&self, rc: brotli_sys::BrotliDecoderResult) -> Result<DeStatus, Error> {
         match rc {
-            // TODO: get info from BrotliDecoderGetErrorCode/BrotliDecoderErrorString
-            // for these decode errors
-            brotli_sys::BROTLI_DECODER_RESULT_ERROR => Err(Error(())),
+            brotli_sys::BROTLI_DECODER_RESULT_ERROR => {
+                let code = unsafe { brotli_sys::BrotliDecoderGetErrorCode(self.state) };
+                let mut err = Error::decode(DecodeErrorKind::from_code(code));
+                err.offset = Some((self.total_in, self.total_out));
+                if err.kind == Some(DecodeErrorKind::FormatWindowBits) {
+                    err.large_window = is_large_window_header(&self.header[..self.header_len]);
+                }
+                if let Some(ref memory) = self.memory {
+                    if memory.exceeded.load(Ordering::SeqCst) {
+                        err.limit = Some(LimitExceeded::Memory);
+                    }
+                }
+                Err(err)
+            }
             brotli_sys::BROTLI_DECODER_RESULT_SUCCESS => Ok(DeStatus::Finished),
             brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT => Ok(DeStatus::NeedInput),
             brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT => Ok(DeStatus::NeedOutput),
@@ -178,6 +583,19 @@ impl Drop for Decompress {
     }
 }
 
+/// Checks whether a stream starting with `header`, of which only the first
+/// byte may be known, carries the large-window signature.
+///
+/// Large-window streams start with the 7-bit window code `0010001`, reserved
+/// in RFC 7932, followed by a zero bit and a 6-bit window size of 10 to 30.
+fn is_large_window_header(header: &[u8]) -> bool {
+    match *header {
+        [0x11] => true,
+        [0x11, b, ..] => (10..=30).contains(&(b & 0x3f)),
+        _ => false,
+    }
+}
+
 /// Decompress data in one go in memory.
 ///
 /// Decompresses the data in `input` into the `output` buffer. The `output`
@@ -194,9 +612,9 @@ pub fn decompress_buf(input: &[u8], output: &mut &mut [u8]) -> Result<usize, Err
             output.as_mut_ptr(),
         )
     };
-    *output = &mut mem::replace(output, &mut [])[..size];
+    *output = &mut mem::take(output)[..size];
     if r == 0 {
-        Err(Error(()))
+        Err(Error::unknown())
     } else {
         Ok(size)
     }
@@ -205,14 +623,77 @@ pub fn decompress_buf(input: &[u8], output: &mut &mut [u8]) -> Result<usize, Err
 impl Compress {
     /// Creates a new compressor ready to encode data into brotli
     pub fn new() -> Compress {
-        unsafe {
-            let state = brotli_sys::BrotliEncoderCreateInstance(None, None, 0 as *mut _);
-            assert!(!state.is_null());
+        Compress::create(None)
+    }
+
+    /// Creates a new compressor configured with `params`, which allocates all
+    /// of its memory from `allocator`.
+    ///
+    /// If `allocator` returns null, the process is aborted through
+    /// `std::alloc::handle_alloc_error`, as it is when any other allocation
+    /// fails.
+    pub fn with_allocator(params: &CompressParams, allocator: Arc<dyn Allocator>) -> Compress {
+        let mut c = Compress::create(Some(allocator));
+        // Parameters are always accepted before compression starts.
+        c.set_params(params).unwrap();
+        c
+    }
 
-            Compress { state: state }
+    fn create(allocator: Option<Arc<dyn Allocator>>) -> Compress {
+        let memory = allocator
+            .clone()
+            .map(|inner| Arc::new(MemoryLimit::new(inner)));
+        let hooks = memory
+            .clone()
+            .map(|memory| Box::new(memory as Arc<dyn Allocator>));
+        // The encoder calls `exit` if an allocation fails, so never let it see
+        // one fail.
+        let (alloc_func, free_func, opaque) = alloc_hooks(&hooks, brotli_alloc_or_abort);
+        let state =
+            unsafe { brotli_sys::BrotliEncoderCreateInstance(alloc_func, free_func, opaque) };
+        assert!(!state.is_null());
+        Compress {
+            state,
+            total_in: 0,
+            total_out: 0,
+            params: CompressParams::new(),
+            dictionary_loaded: false,
+            allocator,
+            _hooks: hooks,
+            memory,
         }
     }
 
+    /// Creates a new compressor primed with the given custom dictionary.
+    ///
+    /// The output can only be decompressed by a decompressor created with the
+    /// same dictionary. Parameters may still be configured with `set_params`
+    /// before compression starts.
+    pub fn with_dictionary(dictionary: &[u8]) -> Compress {
+        Compress::with_shared_dictionary(&SharedDictionary::new(dictionary))
+    }
+
+    /// Creates a new compressor with a shared custom dictionary attached.
+    ///
+    /// The dictionary bytes are not copied, but they are still hashed before
+    /// compression starts.
+    pub fn with_shared_dictionary(dictionary: &SharedDictionary) -> Compress {
+        let mut c = Compress::new();
+        c.params.dictionary = Some(dictionary.clone());
+        c
+    }
+
+    /// Re-creates this compressor with the parameters and dictionary it was
+    /// configured with, so that it can encode a new stream.
+    ///
+    /// This frees the underlying encoder state and allocates a new one, which
+    /// costs as much as creating a new `Compress`.
+    pub fn reset(&mut self) {
+        let mut c = Compress::create(self.allocator.clone());
+        c.set_params(&self.params).unwrap();
+        *self = c;
+    }
+
     // TODO: add the BrotliEncoderOperation variants of
     // BrotliEncoderCompressStream here
 
@@ -286,10 +767,25 @@ impl Compress {
         input: &mut &[u8],
         output: &mut &mut [u8],
     ) -> Result<CoStatus, Error> {
+        if !self.dictionary_loaded {
+            self.dictionary_loaded = true;
+            if let Some(ref dictionary) = self.params.dictionary {
+                let bytes = dictionary.as_bytes();
+                unsafe {
+                    brotli_sys::BrotliEncoderSetCustomDictionary(
+                        self.state,
+                        bytes.len(),
+                        bytes.as_ptr(),
+                    );
+                }
+            }
+        }
         let mut available_in = input.len();
         let mut next_in = input.as_ptr();
         let mut available_out = output.len();
         let mut next_out = output.as_mut_ptr();
+        // libbrotli only updates `total_out` when it writes output
+        let mut total_out = self.total_out as usize;
         let r = unsafe {
             brotli_sys::BrotliEncoderCompressStream(
                 self.state,
@@ -298,23 +794,23 @@ impl Compress {
                 &mut next_in,
                 &mut available_out,
                 &mut next_out,
-                ptr::null_mut(),
+                &mut total_out,
             )
         };
+        self.total_in += (input.len() - available_in) as u64;
+        self.total_out = total_out as u64;
         *input = &input[input.len() - available_in..];
         let out_len = output.len();
-        *output = &mut mem::replace(output, &mut [])[out_len - available_out..];
+        *output = &mut mem::take(output)[out_len - available_out..];
         if r == 0 {
-            return Err(Error(()));
+            return Err(Error::unknown());
         }
         Ok(if op == CompressOp::Process {
             CoStatus::Finished
-        } else if available_in != 0 {
-            CoStatus::Unfinished
-        } else if unsafe { brotli_sys::BrotliEncoderHasMoreOutput(self.state) } == 1 {
-            CoStatus::Unfinished
-        } else if op == CompressOp::Finish
-            && unsafe { brotli_sys::BrotliEncoderIsFinished(self.state) } == 0
+        } else if available_in != 0
+            || unsafe { brotli_sys::BrotliEncoderHasMoreOutput(self.state) } == 1
+            || (op == CompressOp::Finish
+                && unsafe { brotli_sys::BrotliEncoderIsFinished(self.state) } == 0)
         {
             CoStatus::Unfinished
         } else {
@@ -338,45 +834,68 @@ impl Compress {
                 None
             } else {
                 assert!(!ptr.is_null());
+                self.total_out += size_limit as u64;
                 Some(slice::from_raw_parts(ptr, size_limit))
             }
         }
     }
 
+    /// Returns the number of bytes of input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Returns how many bytes of memory this compressor currently has
+    /// allocated, including a small header per allocation.
+    ///
+    /// Memory is only counted when the compressor was created with
+    /// `with_allocator`, otherwise this returns `None`.
+    pub fn memory_usage(&self) -> Option<usize> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.used.load(Ordering::SeqCst))
+    }
+
     /// Configure the parameters of this compression session.
     ///
-    /// Note that this is likely to only successful if called before compression
-    /// starts.
-    pub fn set_params(&mut self, params: &CompressParams) {
-        unsafe {
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_MODE,
-                params.mode,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_QUALITY,
-                params.quality,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_LGWIN,
-                params.lgwin,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_LGBLOCK,
-                params.lgblock,
-            );
-            // TODO: add these two
-            // brotli_sys::BrotliEncoderSetParameter(self.state,
-            //                                       brotli_sys::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING,
-            //                                       params.lgblock);
-            // brotli_sys::BrotliEncoderSetParameter(self.state,
-            //                                       brotli_sys::BROTLI_PARAM_SIZE_HINT,
-            //                                       params.lgblock);
+    /// # Errors
+    ///
+    /// Parameters can only be changed before compression starts, so this
+    /// returns `ParamsError::AlreadyStarted` once `compress` has been called.
+    pub fn set_params(&mut self, params: &CompressParams) -> Result<(), ParamsError> {
+        let values = [
+            (brotli_sys::BROTLI_PARAM_MODE, params.mode),
+            (brotli_sys::BROTLI_PARAM_QUALITY, params.quality),
+            (brotli_sys::BROTLI_PARAM_LGWIN, params.lgwin),
+            (brotli_sys::BROTLI_PARAM_LGBLOCK, params.lgblock),
+            (
+                brotli_sys::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING,
+                params.disable_literal_context_modeling as u32,
+            ),
+            (brotli_sys::BROTLI_PARAM_SIZE_HINT, params.size_hint),
+        ];
+        for &(param, value) in values.iter() {
+            // libbrotli only rejects parameters once the encoder has been
+            // initialized, as `CompressParams` has already checked the ranges.
+            let r = unsafe { brotli_sys::BrotliEncoderSetParameter(self.state, param, value) };
+            if r == 0 {
+                return Err(ParamsError::AlreadyStarted);
+            }
         }
+        // A dictionary attached with `with_dictionary` is kept unless `params`
+        // has one of its own.
+        let dictionary = params
+            .dictionary
+            .clone()
+            .or_else(|| self.params.dictionary.take());
+        self.params = params.clone();
+        self.params.dictionary = dictionary;
+        Ok(())
     }
 }
 
@@ -401,6 +920,20 @@ pub fn compress_buf(
     input: &[u8],
     output: &mut &mut [u8],
 ) -> Result<usize, Error> {
+    if params.dictionary.is_some() {
+        // The one-shot libbrotli API has no way to pass a custom dictionary.
+        let mut c = Compress::new();
+        c.set_params(params).unwrap();
+        let avail_out = output.len();
+        let mut out = &mut output[..];
+        let mut input = input;
+        if c.compress(CompressOp::Finish, &mut input, &mut out)? != CoStatus::Finished {
+            return Err(Error::unknown());
+        }
+        let size = avail_out - out.len();
+        *output = &mut mem::take(output)[..size];
+        return Ok(size);
+    }
     let mut size = output.len();
     let r = unsafe {
         brotli_sys::BrotliEncoderCompress(
@@ -413,29 +946,319 @@ pub fn compress_buf(
             output.as_mut_ptr(),
         )
     };
-    *output = &mut mem::replace(output, &mut [])[..size];
+    *output = &mut mem::take(output)[..size];
     if r == 0 {
-        Err(Error(()))
+        Err(Error::unknown())
     } else {
         Ok(size)
     }
 }
 
+const MAX_DICTIONARY_SIZE: usize = 1 << 24;
+
+/// Returns the arguments for `Brotli*CreateInstance` which route allocations
+/// to `allocator` through `alloc_func`, if there is an allocator. The
+/// allocator is boxed so that the opaque pointer stays valid when the owning
+/// compressor or decompressor moves.
+fn alloc_hooks(
+    allocator: &Option<Box<Arc<dyn Allocator>>>,
+    alloc_func: extern "C" fn(*mut c_void, size_t) -> *mut c_void,
+) -> (
+    brotli_sys::brotli_alloc_func,
+    brotli_sys::brotli_free_func,
+    *mut c_void,
+) {
+    match *allocator {
+        Some(ref allocator) => (
+            Some(alloc_func),
+            Some(brotli_free),
+            &**allocator as *const Arc<dyn Allocator> as *mut c_void,
+        ),
+        None => (None, None, ptr::null_mut()),
+    }
+}
+
+/// Wraps a custom allocator to count the memory in use, and enforces the
+/// memory limit of a decompressor.
+///
+/// Each block is prefixed with a header recording its size, so that frees can
+/// be accounted for.
+struct MemoryLimit {
+    inner: Arc<dyn Allocator>,
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+const MEMORY_HEADER: usize = 16;
+
+impl MemoryLimit {
+    fn new(inner: Arc<dyn Allocator>) -> MemoryLimit {
+        MemoryLimit {
+            inner,
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+}
+
+unsafe impl Allocator for MemoryLimit {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let size = match size.checked_add(MEMORY_HEADER) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+        let used = self
+            .used
+            .fetch_add(size, Ordering::SeqCst)
+            .saturating_add(size);
+        if used > self.limit.load(Ordering::SeqCst) {
+            self.used.fetch_sub(size, Ordering::SeqCst);
+            self.exceeded.store(true, Ordering::SeqCst);
+            return ptr::null_mut();
+        }
+        let block = self.inner.alloc(size);
+        if block.is_null() {
+            self.used.fetch_sub(size, Ordering::SeqCst);
+            return block;
+        }
+        unsafe {
+            (block as *mut usize).write(size);
+            block.add(MEMORY_HEADER)
+        }
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let block = ptr.sub(MEMORY_HEADER);
+        self.used
+            .fetch_sub((block as *const usize).read(), Ordering::SeqCst);
+        self.inner.free(block)
+    }
+}
+
+extern "C" fn brotli_alloc(opaque: *mut c_void, size: size_t) -> *mut c_void {
+    let allocator = unsafe { &*(opaque as *const Arc<dyn Allocator>) };
+    allocator.alloc(size) as *mut c_void
+}
+
+/// Like `brotli_alloc`, but aborts through `handle_alloc_error` instead of
+/// returning null, which the encoder would answer by calling `exit`.
+extern "C" fn brotli_alloc_or_abort(opaque: *mut c_void, size: size_t) -> *mut c_void {
+    let ptr = brotli_alloc(opaque, size);
+    if ptr.is_null() {
+        let layout = Layout::from_size_align(size, 1).unwrap_or_else(|_| Layout::new::<u8>());
+        alloc::handle_alloc_error(layout);
+    }
+    ptr
+}
+
+extern "C" fn brotli_free(opaque: *mut c_void, address: *mut c_void) {
+    let allocator = unsafe { &*(opaque as *const Arc<dyn Allocator>) };
+    unsafe { allocator.free(address as *mut u8) }
+}
+
+impl Default for Decompress {
+    fn default() -> Decompress {
+        Decompress::new()
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Compress {
+        Compress::new()
+    }
+}
+
+impl Error {
+    fn unknown() -> Error {
+        Error {
+            kind: None,
+            offset: None,
+            limit: None,
+            large_window: false,
+        }
+    }
+
+    fn decode(kind: DecodeErrorKind) -> Error {
+        Error {
+            kind: Some(kind),
+            offset: None,
+            limit: None,
+            large_window: false,
+        }
+    }
+
+    /// Returns the reason libbrotli reported for a failed decode, if known.
+    ///
+    /// This is `None` for compression errors and for errors from
+    /// `decompress_buf`, where libbrotli does not report a reason.
+    pub fn decode_kind(&self) -> Option<DecodeErrorKind> {
+        self.kind
+    }
+
+    /// Returns whether this error was caused by a stream which uses the
+    /// large-window brotli extension.
+    ///
+    /// Such streams can't be decoded, see the [limitations](crate#limitations).
+    pub fn is_large_window(&self) -> bool {
+        self.large_window
+    }
+
+    /// Returns the limit set in `DecompressParams` which the stream exceeded,
+    /// if that is what caused this error.
+    pub fn limit_exceeded(&self) -> Option<LimitExceeded> {
+        self.limit
+    }
+
+    /// Returns how many bytes of compressed input had been consumed by the
+    /// decoder when this error occurred.
+    ///
+    /// This is only known for errors returned by `Decompress::decompress`.
+    pub fn input_offset(&self) -> Option<u64> {
+        self.offset.map(|(input, _)| input)
+    }
+
+    /// Returns how many bytes of decompressed output had been produced by the
+    /// decoder when this error occurred.
+    ///
+    /// This is only known for errors returned by `Decompress::decompress`.
+    pub fn output_offset(&self) -> Option<u64> {
+        self.offset.map(|(_, output)| output)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        error::Error::description(self).fmt(f)
+        match (self.limit, self.kind) {
+            (Some(limit), _) => write!(f, "brotli error: {}", limit)?,
+            (None, Some(kind)) => write!(f, "brotli error: {}", kind)?,
+            (None, None) => f.write_str("brotli error")?,
+        }
+        if self.is_large_window() {
+            f.write_str(" (large-window brotli streams are not supported)")?;
+        }
+        if let Some((input, output)) = self.offset {
+            write!(f, " at input byte {} (output byte {})", input, output)?;
+        }
+        Ok(())
     }
 }
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        "brotli error"
+impl error::Error for Error {}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitExceeded::Memory => f.write_str("decoder memory limit exceeded"),
+            LimitExceeded::OutputSize => f.write_str("decompressed size limit exceeded"),
+            LimitExceeded::Ratio => f.write_str("decompression ratio limit exceeded"),
+        }
     }
 }
 
 impl From<Error> for io::Error {
-    fn from(_err: Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, "brotli error")
+    /// Converts a brotli error into an I/O error, keeping the original error
+    /// as the source.
+    ///
+    /// Invalid input, and input exceeding the output limits, maps to
+    /// `InvalidData` and failed allocations map to `OutOfMemory`; everything
+    /// else is reported as `Other`.
+    fn from(err: Error) -> io::Error {
+        let kind = match err.kind {
+            _ if err.limit == Some(LimitExceeded::OutputSize) => io::ErrorKind::InvalidData,
+            _ if err.limit == Some(LimitExceeded::Ratio) => io::ErrorKind::InvalidData,
+            Some(kind) if kind.is_format_error() => io::ErrorKind::InvalidData,
+            Some(kind) if kind.is_alloc_error() => io::ErrorKind::OutOfMemory,
+            Some(DecodeErrorKind::InvalidArguments) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl DecodeErrorKind {
+    fn from_code(code: brotli_sys::BrotliDecoderErrorCode) -> DecodeErrorKind {
+        DECODE_ERROR_CODES
+            .iter()
+            .find(|&&(c, _)| c == code)
+            .map(|&(_, kind)| kind)
+            .unwrap_or(DecodeErrorKind::Unknown(code))
+    }
+
+    /// Returns the `BROTLI_DECODER_ERROR_*` code for this error.
+    pub fn code(&self) -> i32 {
+        match *self {
+            DecodeErrorKind::Unknown(code) => code,
+            kind => DECODE_ERROR_CODES
+                .iter()
+                .find(|&&(_, k)| k == kind)
+                .map(|&(c, _)| c)
+                .unwrap(),
+        }
+    }
+
+    /// Returns whether this error was caused by invalid input data.
+    pub fn is_format_error(&self) -> bool {
+        use self::DecodeErrorKind::*;
+
+        matches!(
+            *self,
+            FormatExuberantNibble
+                | FormatReserved
+                | FormatExuberantMetaNibble
+                | FormatSimpleHuffmanAlphabet
+                | FormatSimpleHuffmanSame
+                | FormatClSpace
+                | FormatHuffmanSpace
+                | FormatContextMapRepeat
+                | FormatBlockLength1
+                | FormatBlockLength2
+                | FormatTransform
+                | FormatDictionary
+                | FormatWindowBits
+                | FormatPadding1
+                | FormatPadding2
+        )
+    }
+
+    /// Returns whether this error was caused by a failed memory allocation.
+    pub fn is_alloc_error(&self) -> bool {
+        use self::DecodeErrorKind::*;
+
+        matches!(
+            *self,
+            AllocContextModes
+                | AllocTreeGroups
+                | AllocContextMap
+                | AllocRingBuffer1
+                | AllocRingBuffer2
+                | AllocBlockTypeTrees
+        )
+    }
+
+    /// Returns the message libbrotli associates with this error, as returned
+    /// by `BrotliDecoderErrorString`.
+    ///
+    /// libbrotli has no message for `Unknown` codes, and returns `INVALID`.
+    pub fn message(&self) -> &'static str {
+        unsafe {
+            let ptr = brotli_sys::BrotliDecoderErrorString(self.code());
+            assert!(!ptr.is_null());
+            str::from_utf8(CStr::from_ptr(ptr).to_bytes()).unwrap()
+        }
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeErrorKind::Unknown(code) => write!(f, "unknown decoder error code {}", code),
+            _ => f.write_str(self.message()),
+        }
     }
 }
 
@@ -450,6 +1273,393 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn decompress_error_kind() {
+        let mut d = Decompress::new();
+        let err = d
+            .decompress(&mut &[0xff; 1024][..], &mut &mut [0; 2048][..])
+            .unwrap_err();
+        let kind = err.decode_kind().unwrap();
+        assert!(kind.is_format_error());
+        assert!(!kind.is_alloc_error());
+        assert!(err
+            .to_string()
+            .starts_with(&format!("brotli error: {}", kind.message())));
+    }
+
+    #[test]
+    fn decode_error_codes() {
+        for &(code, kind) in DECODE_ERROR_CODES.iter() {
+            assert_eq!(DecodeErrorKind::from_code(code), kind);
+            assert_eq!(kind.code(), code);
+            assert_ne!(kind.message(), "INVALID");
+        }
+        let kind = DecodeErrorKind::from_code(-99);
+        assert_eq!(kind, DecodeErrorKind::Unknown(-99));
+        assert_eq!(kind.code(), -99);
+        assert!(!kind.is_format_error() && !kind.is_alloc_error());
+        assert_eq!(kind.to_string(), "unknown decoder error code -99");
+    }
+
+    #[test]
+    fn decompress_large_window() {
+        // A large-window stream header, as written by `bro --large_window`.
+        let mut d = Decompress::new();
+        let err = d
+            .decompress(&mut &[0x11, 0x0b, 0x00][..], &mut &mut [0; 16][..])
+            .unwrap_err();
+        assert_eq!(err.decode_kind(), Some(DecodeErrorKind::FormatWindowBits));
+        assert!(err.is_large_window());
+        assert!(err.to_string().contains("large-window"));
+
+        // Fed a byte at a time, the error is raised before the window size.
+        let mut d = Decompress::new();
+        let err = d
+            .decompress(&mut &[0x11][..], &mut &mut [0; 16][..])
+            .unwrap_err();
+        assert!(err.is_large_window());
+
+        // The same reserved window code, but without the large-window
+        // signature around it.
+        for header in [[0x91, 0x0b, 0x00], [0x11, 0x3f, 0x00]].iter() {
+            let mut d = Decompress::new();
+            let err = d
+                .decompress(&mut &header[..], &mut &mut [0; 16][..])
+                .unwrap_err();
+            assert_eq!(err.decode_kind(), Some(DecodeErrorKind::FormatWindowBits));
+            assert!(!err.is_large_window());
+            assert!(!err.to_string().contains("large-window"));
+        }
+    }
+
+    #[test]
+    fn decompress_error_offset() {
+        // A flushed stream ends on a byte boundary, after which 0xff encodes
+        // a final empty meta-block with non-zero padding bits.
+        let mut c = Compress::new();
+        let mut data = [0; 128];
+        let n = {
+            let mut out = &mut data[..];
+            assert_eq!(
+                c.compress(CompressOp::Flush, &mut &b"hello!"[..], &mut out),
+                Ok(CoStatus::Finished)
+            );
+            128 - out.len()
+        };
+        data[n] = 0xff;
+
+        let mut d = Decompress::new();
+        let mut dst = [0; 128];
+        assert_eq!(
+            d.decompress(&mut &data[..n], &mut &mut dst[..]),
+            Ok(DeStatus::NeedInput)
+        );
+        let err = d
+            .decompress(&mut &data[n..n + 1], &mut &mut dst[..])
+            .unwrap_err();
+        assert!(err.decode_kind().unwrap().is_format_error());
+        assert_eq!(err.input_offset(), Some(n as u64 + 1));
+        assert_eq!(err.output_offset(), Some(6));
+        assert!(decompress_buf(&[0xff; 4], &mut &mut dst[..])
+            .unwrap_err()
+            .input_offset()
+            .is_none());
+    }
+
+    #[test]
+    fn dictionary_roundtrip() {
+        let dict = b"a brotli dictionary shared by both ends of the stream";
+        let input = b"a brotli dictionary shared by both ends, and then some";
+
+        let mut params = CompressParams::new();
+        params.dictionary(dict);
+        let mut data = [0; 128];
+        let mut data = &mut data[..];
+        compress_buf(&params, input, &mut data).unwrap();
+
+        let mut plain = [0; 128];
+        let mut plain = &mut plain[..];
+        compress_buf(&CompressParams::new(), input, &mut plain).unwrap();
+        assert!(data.len() < plain.len());
+
+        let mut d = Decompress::with_dictionary(dict);
+        let mut dst = [0; 128];
+        {
+            let mut data = &data[..];
+            let mut dst = &mut dst[..];
+            assert_eq!(d.decompress(&mut data, &mut dst), Ok(DeStatus::Finished));
+        }
+        assert_eq!(&dst[..input.len()], &input[..]);
+
+        let mut data2 = [0; 128];
+        let mut c = Compress::with_dictionary(dict);
+        c.set_params(CompressParams::new().quality(9).unwrap())
+            .unwrap();
+        {
+            let mut out = &mut data2[..];
+            assert_eq!(
+                c.compress(CompressOp::Finish, &mut &input[..], &mut out),
+                Ok(CoStatus::Finished)
+            );
+        }
+        let mut d = Decompress::with_dictionary(dict);
+        let mut dst = [0; 128];
+        assert_eq!(
+            d.decompress(&mut &data2[..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(&dst[..input.len()], &input[..]);
+    }
+
+    #[test]
+    fn reset() {
+        let dict = b"a dictionary that both streams are compressed against";
+        let input = b"a dictionary that both streams share";
+        let mut params = CompressParams::new();
+        params.quality(9).unwrap();
+        let mut c = Compress::with_dictionary(dict);
+        c.set_params(&params).unwrap();
+        let mut d = Decompress::with_dictionary(dict);
+
+        let mut first = None;
+        for _ in 0..2 {
+            let mut data = [0; 128];
+            let n = {
+                let mut out = &mut data[..];
+                assert_eq!(
+                    c.compress(CompressOp::Finish, &mut &input[..], &mut out),
+                    Ok(CoStatus::Finished)
+                );
+                128 - out.len()
+            };
+            // The parameters and dictionary survive a reset, so both streams
+            // are identical.
+            let data = data[..n].to_vec();
+            assert_eq!(*first.get_or_insert_with(|| data.clone()), data);
+
+            let mut dst = [0; 128];
+            assert_eq!(
+                d.decompress(&mut &data[..], &mut &mut dst[..]),
+                Ok(DeStatus::Finished)
+            );
+            assert_eq!(&dst[..input.len()], &input[..]);
+
+            c.reset();
+            d.reset();
+        }
+    }
+
+    #[test]
+    fn memory_limit() {
+        let input = (0..1 << 20)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut data = vec![0; 1 << 21];
+        let mut data = &mut data[..];
+        compress_buf(
+            &CompressParams::preset(::Preset::Realtime),
+            &input,
+            &mut data,
+        )
+        .unwrap();
+        let mut dst = vec![0; input.len()];
+
+        let mut d = Decompress::from_params(DecompressParams::new().memory_limit(64 * 1024));
+        let err = d.decompress(&mut &data[..], &mut &mut dst[..]).unwrap_err();
+        assert_eq!(err.limit_exceeded(), Some(LimitExceeded::Memory));
+        assert!(err.decode_kind().unwrap().is_alloc_error());
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::OutOfMemory);
+
+        let mut d = Decompress::from_params(DecompressParams::new().memory_limit(4 << 20));
+        assert_eq!(
+            d.decompress(&mut &data[..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(dst, input);
+    }
+
+    #[test]
+    fn totals() {
+        let input = "totals ".repeat(10_000);
+        let mut c = Compress::new();
+        let mut data = vec![0; 64];
+        let n = {
+            // Leave most of the output inside the compressor.
+            let mut out = &mut data[..];
+            c.compress(CompressOp::Finish, &mut input.as_bytes(), &mut out)
+                .unwrap();
+            64 - out.len()
+        };
+        assert_eq!(c.total_in(), input.len() as u64);
+        assert_eq!(c.total_out(), n as u64);
+        data.truncate(n);
+        while let Some(output) = c.take_output(None) {
+            data.extend_from_slice(output);
+        }
+        assert_eq!(c.total_out(), data.len() as u64);
+
+        let mut d = Decompress::new();
+        let mut dst = vec![0; input.len()];
+        assert_eq!(
+            d.decompress(&mut &data[..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), input.len() as u64);
+        c.reset();
+        assert_eq!((c.total_in(), c.total_out()), (0, 0));
+    }
+
+    #[test]
+    fn memory_usage() {
+        assert_eq!(Compress::new().memory_usage(), None);
+        assert_eq!(Decompress::new().memory_usage(), None);
+
+        let input = (0..1 << 20)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut params = CompressParams::new();
+        params.quality(5).unwrap().lgwin(20).unwrap();
+        let allocator = Arc::new(GlobalAllocator(System));
+        let mut c = Compress::with_allocator(&params, allocator.clone());
+        let idle = c.memory_usage().unwrap();
+        assert!(idle > 0);
+
+        let mut data = vec![0; 1 << 21];
+        let n = {
+            let len = data.len();
+            let mut out = &mut data[..];
+            for chunk in input.chunks(1 << 16) {
+                let mut chunk = chunk;
+                c.compress(CompressOp::Process, &mut chunk, &mut out)
+                    .unwrap();
+                assert!(chunk.is_empty());
+                let used = c.memory_usage().unwrap();
+                assert!(used > idle);
+                assert!(used <= params.estimated_peak_memory(input.len()));
+            }
+            c.compress(CompressOp::Finish, &mut &[][..], &mut out)
+                .unwrap();
+            len - out.len()
+        };
+        c.reset();
+        assert_eq!(c.memory_usage(), Some(idle));
+
+        let dparams = DecompressParams::new();
+        let mut d = Decompress::with_allocator(&dparams, allocator);
+        let mut dst = vec![0; input.len()];
+        assert_eq!(
+            d.decompress(&mut &data[..n], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(dst, input);
+        assert!(d.memory_usage().unwrap() <= dparams.estimated_peak_memory(20));
+        assert!(
+            dparams.estimated_peak_memory(20) < DecompressParams::new().estimated_peak_memory(24)
+        );
+        assert!(
+            DecompressParams::new()
+                .memory_limit(1 << 20)
+                .estimated_peak_memory(24)
+                < 2 << 20
+        );
+    }
+
+    #[test]
+    fn estimated_peak_memory() {
+        let input = (0..1 << 18)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let allocator = Arc::new(GlobalAllocator(System));
+        for quality in 0..=11 {
+            let mut params = CompressParams::new();
+            params.quality(quality).unwrap().lgwin(18).unwrap();
+            let estimate = params.estimated_peak_memory(input.len());
+            let mut c = Compress::with_allocator(&params, allocator.clone());
+            let mut data = vec![0; 1 << 19];
+            let mut out = &mut data[..];
+            c.compress(CompressOp::Process, &mut &input[..], &mut out)
+                .unwrap();
+            assert!(c.memory_usage().unwrap() <= estimate, "{}", quality);
+            c.compress(CompressOp::Finish, &mut &[][..], &mut out)
+                .unwrap();
+            assert!(c.memory_usage().unwrap() <= estimate, "{}", quality);
+        }
+    }
+
+    #[test]
+    fn output_limits() {
+        let input = vec![b'a'; 100_000];
+        let mut data = vec![0; 1024];
+        let mut data = &mut data[..];
+        compress_buf(&CompressParams::new(), &input, &mut data).unwrap();
+        let mut dst = vec![0; input.len()];
+
+        let mut d = Decompress::from_params(DecompressParams::new().max_output_size(1000));
+        let err = {
+            let mut out = &mut dst[..];
+            let err = d.decompress(&mut &data[..], &mut out).unwrap_err();
+            assert_eq!(out.len(), input.len() - 1000);
+            err
+        };
+        assert_eq!(err.limit_exceeded(), Some(LimitExceeded::OutputSize));
+        assert_eq!(err.output_offset(), Some(1000));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+
+        let mut d = Decompress::from_params(DecompressParams::new().max_ratio(10));
+        let err = d.decompress(&mut &data[..], &mut &mut dst[..]).unwrap_err();
+        assert_eq!(err.limit_exceeded(), Some(LimitExceeded::Ratio));
+        assert!(err.output_offset().unwrap() <= 10 * data.len() as u64);
+
+        let mut params = DecompressParams::new();
+        params
+            .max_output_size(input.len() as u64)
+            .max_ratio(100_000);
+        let mut d = Decompress::from_params(&params);
+        assert_eq!(
+            d.decompress(&mut &data[..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(dst, input);
+    }
+
+    #[test]
+    fn params_validation() {
+        let mut params = CompressParams::new();
+        assert!(params.quality(11).is_ok());
+        assert_eq!(params.quality(12).unwrap_err(), ParamsError::Quality(12));
+        assert!(params.lgwin(10).is_ok());
+        assert_eq!(params.lgwin(40).unwrap_err(), ParamsError::Lgwin(40));
+        assert_eq!(params.lgwin(9).unwrap_err(), ParamsError::Lgwin(9));
+        assert!(params.lgblock(0).is_ok());
+        assert!(params.lgblock(16).is_ok());
+        assert_eq!(params.lgblock(15).unwrap_err(), ParamsError::Lgblock(15));
+        assert_eq!(params.get_lgwin(), 10);
+        assert_eq!(params.get_lgblock(), 16);
+
+        let mut c = Compress::new();
+        c.set_params(&params).unwrap();
+        let mut out = [0; 64];
+        c.compress(CompressOp::Process, &mut &b"data"[..], &mut &mut out[..])
+            .unwrap();
+        assert_eq!(c.set_params(&params), Err(ParamsError::AlreadyStarted));
+    }
+
+    #[test]
+    fn io_error_kind() {
+        let mut d = Decompress::new();
+        let err = d
+            .decompress(&mut &[0xff; 1024][..], &mut &mut [0; 2048][..])
+            .unwrap_err();
+        let io_err = io::Error::from(err.clone());
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+        let inner = io_err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert_eq!(*inner, err);
+
+        let io_err = io::Error::from(Error::decode(DecodeErrorKind::AllocRingBuffer1));
+        assert_eq!(io_err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
     #[test]
     fn compress_buf_smoke() {
         let mut data = [0; 128];
@@ -1,11 +1,15 @@
 //! Reader-based compression/decompression streams
 
+use std::cmp;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
 
 use bufread;
 
-use super::CompressParams;
+use super::{CompressParams, DecompressParams};
+use dictionary::SharedDictionary;
+
+const MIN_BUF_SIZE: usize = 8 * 1024;
 
 /// A compression stream which wraps an uncompressed stream of data. Compressed
 /// data will be read from the stream.
@@ -40,6 +44,33 @@ impl<R: Read> BrotliEncoder<R> {
         }
     }
 
+    /// Configure the compression parameters of this encoder for an input
+    /// whose total length may be known in advance.
+    ///
+    /// If `input_len` is known it is passed to the compressor as a size hint,
+    /// and the read buffer is sized to the input rather than to the window.
+    pub fn with_size_hint(
+        r: R,
+        params: &CompressParams,
+        input_len: Option<u64>,
+    ) -> BrotliEncoder<R> {
+        let len = match input_len {
+            Some(len) => len,
+            None => return BrotliEncoder::from_params(r, params),
+        };
+        // Clamp in u64 first, `len` may not fit in a usize.
+        let capacity = cmp::min(
+            len.max(MIN_BUF_SIZE as u64),
+            params.get_lgwin_readable() as u64,
+        ) as usize;
+        BrotliEncoder {
+            inner: bufread::BrotliEncoder::from_params(
+                BufReader::with_capacity(capacity, r),
+                params.clone().size_hint(len),
+            ),
+        }
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -53,10 +84,45 @@ impl<R: Read> BrotliEncoder<R> {
         self.inner.get_mut().get_mut()
     }
 
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    ///
+    /// Input is read from the underlying stream in chunks, so this may be
+    /// less than the number of bytes read from it.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn into_inner(self) -> R {
         self.inner.into_inner().into_inner()
     }
+
+    /// Inserts a metadata block into the compressed stream.
+    ///
+    /// The block is emitted by the following reads, after everything
+    /// compressed from the input so far. See
+    /// `bufread::BrotliEncoder::write_metadata` for details.
+    pub fn write_metadata(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_metadata(data)
+    }
+
+    /// Resets the state of this encoder entirely, swapping out the input
+    /// stream for another.
+    ///
+    /// The compression parameters are kept. Any data not yet read from the
+    /// current stream is discarded, as are any metadata blocks not yet
+    /// emitted, and the previous input stream is returned.
+    pub fn reset(&mut self, r: R) -> R {
+        let capacity = self.inner.get_ref().capacity();
+        self.inner
+            .reset(BufReader::with_capacity(capacity, r))
+            .into_inner()
+    }
 }
 
 impl<R: Read> Read for BrotliEncoder<R> {
@@ -74,6 +140,29 @@ impl<R: Read> BrotliDecoder<R> {
         }
     }
 
+    /// Create a new decompression stream which will decompress data
+    /// compressed with the given custom dictionary.
+    pub fn with_dictionary(r: R, dictionary: &[u8]) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::with_dictionary(BufReader::new(r), dictionary),
+        }
+    }
+
+    /// Create a new decompression stream which will decompress data
+    /// compressed with the given shared custom dictionary.
+    pub fn with_shared_dictionary(r: R, dictionary: &SharedDictionary) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::with_shared_dictionary(BufReader::new(r), dictionary),
+        }
+    }
+
+    /// Create a new decompression stream with custom decompression parameters.
+    pub fn from_params(r: R, params: &DecompressParams) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::from_params(BufReader::new(r), params),
+        }
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -87,10 +176,35 @@ impl<R: Read> BrotliDecoder<R> {
         self.inner.get_mut().get_mut()
     }
 
+    /// Returns the number of bytes of compressed input consumed so far.
+    ///
+    /// Input is read from the underlying stream in chunks, so this may be
+    /// less than the number of bytes read from it.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn into_inner(self) -> R {
         self.inner.into_inner().into_inner()
     }
+
+    /// Resets the state of this decoder entirely, swapping out the input
+    /// stream for another.
+    ///
+    /// The decompression parameters are kept. Any data not yet read from the
+    /// current stream is discarded, and the previous input stream is returned.
+    pub fn reset(&mut self, r: R) -> R {
+        let capacity = self.inner.get_ref().capacity();
+        self.inner
+            .reset(BufReader::with_capacity(capacity, r))
+            .into_inner()
+    }
 }
 
 impl<R: Read> Read for BrotliDecoder<R> {
@@ -101,8 +215,11 @@ impl<R: Read> Read for BrotliDecoder<R> {
 
 #[cfg(test)]
 mod tests {
+    use raw;
     use read::{BrotliDecoder, BrotliEncoder};
+    use std::io;
     use std::io::prelude::*;
+    use {CompressParams, DecompressParams};
 
     use rand::distributions::Standard;
     use rand::{thread_rng, Rng};
@@ -130,6 +247,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::op_ref)]
     fn smoke3() {
         let m = vec![3u8; 128 * 1024 + 1];
         let c = BrotliEncoder::new(&m[..], 6);
@@ -140,6 +258,30 @@ mod tests {
     }
 
     #[test]
+    fn size_hint() {
+        let m = vec![3u8; 100];
+        let mut params = CompressParams::new();
+        params.disable_literal_context_modeling(true);
+        let c = BrotliEncoder::with_size_hint(&m[..], &params, Some(m.len() as u64));
+        let mut d = BrotliDecoder::new(c);
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert_eq!(data, m);
+    }
+
+    #[test]
+    fn huge_size_hint() {
+        let m = vec![3u8; 100];
+        let params = CompressParams::new();
+        let c = BrotliEncoder::with_size_hint(&m[..], &params, Some(u64::MAX));
+        let mut d = BrotliDecoder::new(c);
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert_eq!(data, m);
+    }
+
+    #[test]
+    #[allow(clippy::uninit_vec, clippy::map_clone, clippy::op_ref)]
     fn self_terminating() {
         let m = vec![3u8; 128 * 1024 + 1];
         let mut c = BrotliEncoder::new(&m[..], 6);
@@ -190,6 +332,45 @@ mod tests {
         assert!(d.read(&mut data).unwrap() == 0);
     }
 
+    #[test]
+    fn decode_error_kind() {
+        let mut d = BrotliDecoder::new(&[0xff; 64][..]);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<raw::Error>().unwrap();
+        assert!(err.decode_kind().unwrap().is_format_error());
+    }
+
+    #[test]
+    fn truncated() {
+        let m = vec![3u8; 1024];
+        let mut data = Vec::new();
+        BrotliEncoder::new(&m[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut d = BrotliDecoder::new(&data[..data.len() - 1]);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn dictionary() {
+        let dict = b"some text which the dictionary and the input share";
+        let m = b"some text which the input and the dictionary share";
+        let mut params = CompressParams::new();
+        params.dictionary(dict);
+        let c = BrotliEncoder::from_params(&m[..], &params);
+        let mut d = BrotliDecoder::with_dictionary(c, dict);
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert_eq!(data, &m[..]);
+
+        let c = BrotliEncoder::from_params(&m[..], &params);
+        let mut d = BrotliDecoder::from_params(c, DecompressParams::new().dictionary(dict));
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert_eq!(data, &m[..]);
+    }
+
     #[test]
     fn qc() {
         ::quickcheck::quickcheck(test as fn(_) -> _);
@@ -202,4 +383,78 @@ mod tests {
             v == v2
         }
     }
+
+    #[test]
+    fn reset() {
+        let mut c = BrotliEncoder::new(&b"first stream"[..], 6);
+        let mut first = Vec::new();
+        c.read_to_end(&mut first).unwrap();
+        c.reset(&b"second stream"[..]);
+        let mut second = Vec::new();
+        c.read_to_end(&mut second).unwrap();
+
+        let mut d = BrotliDecoder::new(&first[..]);
+        let mut data = String::new();
+        d.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "first stream");
+        d.reset(&second[..]);
+        data.clear();
+        d.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "second stream");
+    }
+
+    #[test]
+    fn memory_limit() {
+        let m = "memory limit ".repeat(100_000);
+        let c = BrotliEncoder::new(m.as_bytes(), 6);
+        let mut d = BrotliDecoder::from_params(c, DecompressParams::new().memory_limit(1024));
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<raw::Error>().unwrap();
+        assert_eq!(err.limit_exceeded(), Some(raw::LimitExceeded::Memory));
+    }
+
+    #[test]
+    fn totals() {
+        let m = "totals ".repeat(10_000);
+        let mut c = BrotliEncoder::new(m.as_bytes(), 6);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert_eq!(c.total_in(), m.len() as u64);
+        assert_eq!(c.total_out(), data.len() as u64);
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        let mut dst = Vec::new();
+        d.read_to_end(&mut dst).unwrap();
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), m.len() as u64);
+    }
+
+    #[test]
+    fn metadata() {
+        let m = "metadata ".repeat(10_000);
+        let mut c = BrotliEncoder::new(m.as_bytes(), 6);
+        c.write_metadata(b"header").unwrap();
+        let mut data = Vec::new();
+        let mut buf = [0; 5];
+        let mut inserted = false;
+        loop {
+            let n = c.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+            if c.total_in() != 0 && !inserted {
+                c.write_metadata(&[b'x'; 1000]).unwrap();
+                inserted = true;
+            }
+        }
+        assert!(c.write_metadata(b"too late").is_err());
+        assert!(data.windows(6).any(|w| w == b"header"));
+        assert!(data.windows(1000).any(|w| w == &[b'x'; 1000][..]));
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        let mut dst = Vec::new();
+        d.read_to_end(&mut dst).unwrap();
+        assert!(dst == m.as_bytes());
+    }
 }
@@ -0,0 +1,403 @@
+//! Compression and decompression of `Stream`s of `Bytes`
+//!
+//! `compress_stream` and `decompress_stream` wrap a stream of chunks, such as
+//! an HTTP body, and produce a stream of compressed or decompressed chunks.
+//! Each output chunk is copied once, from the coder's internal buffer into
+//! its own `Bytes`, as soon as the coder produces it.
+//!
+//! By default the compressor decides when to emit output, which gives the
+//! best compression. With `CompressStream::flush_chunks` the compressor is
+//! flushed at the end of every input chunk instead, so that the other end can
+//! decompress each chunk as soon as it arrives, which suits streaming
+//! responses such as server-sent events.
+//!
+//! This module is only available with the `stream` feature enabled.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures_core::Stream;
+
+use codec::{Decoder, Encoder};
+use raw::{Compress, CompressOp, Decompress};
+use {CompressParams, DecompressParams};
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum State {
+    Reading,
+    Flushing,
+    Finishing,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum DecompressState {
+    Reading,
+    Finishing,
+    Done,
+    Failed,
+}
+
+/// A stream of compressed chunks, created by `compress_stream`.
+pub struct CompressStream<S> {
+    stream: S,
+    data: Encoder,
+    input: Bytes,
+    flush_chunks: bool,
+    state: State,
+}
+
+/// A stream of decompressed chunks, created by `decompress_stream`.
+pub struct DecompressStream<S> {
+    stream: S,
+    data: Decoder,
+    input: Bytes,
+    state: DecompressState,
+}
+
+/// Compresses a stream of chunks with `params`.
+///
+/// An error from `stream` is passed on, and ends the compressed stream.
+pub fn compress_stream<S>(stream: S, params: CompressParams) -> CompressStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let mut data = Compress::new();
+    data.set_params(&params).unwrap();
+    CompressStream {
+        stream,
+        data: Encoder::new(data),
+        input: Bytes::new(),
+        flush_chunks: false,
+        state: State::Reading,
+    }
+}
+
+/// Decompresses a stream of compressed chunks with `params`.
+///
+/// A truncated stream is reported as an `UnexpectedEof` error, and anything
+/// following the end of the compressed stream is ignored. An error from
+/// `stream` is passed on, and ends the decompressed stream.
+pub fn decompress_stream<S>(stream: S, params: DecompressParams) -> DecompressStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    DecompressStream {
+        stream,
+        data: Decoder::new(Decompress::from_params(&params)),
+        input: Bytes::new(),
+        state: DecompressState::Reading,
+    }
+}
+
+impl<S> CompressStream<S> {
+    /// Configures whether the compressor is flushed at the end of each input
+    /// chunk.
+    ///
+    /// When enabled, everything read from the input stream so far can be
+    /// decompressed from the output produced so far, at some cost in
+    /// compression ratio. An empty input chunk also causes a flush.
+    ///
+    /// Default: `false`
+    pub fn flush_chunks(&mut self, flush: bool) -> &mut CompressStream<S> {
+        self.flush_chunks = flush;
+        self
+    }
+
+    /// Acquires a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Acquires a mutable reference to the underlying stream.
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this stream is continued to be used.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this stream, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Stream for CompressStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        loop {
+            if this.state == State::Failed {
+                return Poll::Ready(None);
+            }
+            if let Some(data) = this.data.data.take_output(None) {
+                return Poll::Ready(Some(Ok(Bytes::copy_from_slice(data))));
+            }
+            let result = match this.state {
+                State::Reading if !this.input.is_empty() => this.data.write(&this.input).map(|n| {
+                    this.input.advance(n);
+                    if this.input.is_empty() && this.flush_chunks {
+                        this.state = State::Flushing;
+                    }
+                }),
+                State::Reading => match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                    Some(Ok(chunk)) => {
+                        if chunk.is_empty() && this.flush_chunks {
+                            this.state = State::Flushing;
+                        }
+                        this.input = chunk;
+                        Ok(())
+                    }
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        this.state = State::Finishing;
+                        Ok(())
+                    }
+                },
+                State::Flushing => this.data.flush(CompressOp::Flush).map(|flushed| {
+                    if flushed {
+                        this.state = State::Reading;
+                    }
+                }),
+                State::Finishing => this.data.flush(CompressOp::Finish).map(|finished| {
+                    if finished {
+                        this.state = State::Done;
+                    }
+                }),
+                State::Done | State::Failed => return Poll::Ready(None),
+            };
+            if let Err(e) = result {
+                this.state = State::Failed;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+impl<S> DecompressStream<S> {
+    /// Acquires a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Acquires a mutable reference to the underlying stream.
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this stream is continued to be used.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
+    /// Consumes this stream, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Stream for DecompressStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        loop {
+            if this.state == DecompressState::Failed {
+                return Poll::Ready(None);
+            }
+            if let Some(data) = this.data.data.take_output(None) {
+                return Poll::Ready(Some(Ok(Bytes::copy_from_slice(data))));
+            }
+            let result = match this.state {
+                // Nothing is consumed once the end of the compressed stream
+                // has been reached.
+                DecompressState::Reading if !this.input.is_empty() => {
+                    match this.data.write(&this.input) {
+                        Ok(0) => {
+                            this.state = DecompressState::Finishing;
+                            Ok(())
+                        }
+                        Ok(n) => {
+                            this.input.advance(n);
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                DecompressState::Reading => {
+                    match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                        Some(Ok(chunk)) => {
+                            this.input = chunk;
+                            Ok(())
+                        }
+                        Some(Err(e)) => Err(e),
+                        None => {
+                            this.state = DecompressState::Finishing;
+                            Ok(())
+                        }
+                    }
+                }
+                DecompressState::Finishing => this.data.finish().map(|finished| {
+                    if finished {
+                        this.state = DecompressState::Done;
+                    }
+                }),
+                DecompressState::Done | DecompressState::Failed => return Poll::Ready(None),
+            };
+            if let Err(e) = result {
+                this.state = DecompressState::Failed;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures_core::Stream;
+    use futures_executor::block_on;
+    use futures_util::stream::{self, StreamExt};
+
+    use super::{compress_stream, decompress_stream};
+    use raw::{DeStatus, Decompress};
+    use {CompressParams, DecompressParams};
+
+    fn chunks(data: &[u8], size: usize) -> Vec<io::Result<Bytes>> {
+        data.chunks(size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect()
+    }
+
+    fn concat<S: Stream<Item = io::Result<Bytes>> + Unpin>(s: S) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for chunk in block_on(s.collect::<Vec<_>>()) {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = "stream of bytes ".repeat(10_000);
+        let c = compress_stream(
+            stream::iter(chunks(data.as_bytes(), 1000)),
+            CompressParams::new(),
+        );
+        let compressed = concat(c).unwrap();
+
+        let mut d = decompress_stream(
+            stream::iter(chunks(&compressed, 7)),
+            DecompressParams::new(),
+        );
+        let decompressed = concat(&mut d).unwrap();
+        assert!(decompressed == data.as_bytes());
+        assert_eq!(d.total_in(), compressed.len() as u64);
+        assert_eq!(d.total_out(), data.len() as u64);
+    }
+
+    #[test]
+    fn empty() {
+        let c = compress_stream(stream::iter(Vec::new()), CompressParams::new());
+        let compressed = concat(c).unwrap();
+        assert!(!compressed.is_empty());
+
+        let d = decompress_stream(
+            stream::iter(chunks(&compressed, 1)),
+            DecompressParams::new(),
+        );
+        assert!(concat(d).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flush_chunks() {
+        let input = || {
+            stream::iter(vec![
+                Ok(Bytes::from_static(b"hello, ")),
+                Ok(Bytes::from_static(b"world")),
+            ])
+        };
+
+        // The first chunk of output is everything, unless the compressor is
+        // flushed after each chunk of input.
+        let mut c = compress_stream(input(), CompressParams::new());
+        let first = block_on(c.next()).unwrap().unwrap();
+        let mut out = [0; 64];
+        let mut d = Decompress::new();
+        let status = d.decompress(&mut &first[..], &mut &mut out[..]);
+        assert_eq!(status, Ok(DeStatus::Finished));
+        assert_eq!(&out[..12], b"hello, world");
+
+        let mut c = compress_stream(input(), CompressParams::new());
+        c.flush_chunks(true);
+        let first = block_on(c.next()).unwrap().unwrap();
+        let mut d = Decompress::new();
+        let status = d.decompress(&mut &first[..], &mut &mut out[..]);
+        assert_eq!(status, Ok(DeStatus::NeedInput));
+        assert_eq!(&out[..7], b"hello, ");
+
+        let rest = concat(c).unwrap();
+        let status = d.decompress(&mut &rest[..], &mut &mut out[7..]);
+        assert_eq!(status, Ok(DeStatus::Finished));
+        assert_eq!(&out[..12], b"hello, world");
+    }
+
+    #[test]
+    fn truncated() {
+        let c = compress_stream(
+            stream::iter(chunks(b"truncated stream", 4)),
+            CompressParams::new(),
+        );
+        let compressed = concat(c).unwrap();
+        let d = decompress_stream(
+            stream::iter(chunks(&compressed[..compressed.len() - 1], 3)),
+            DecompressParams::new(),
+        );
+        let err = concat(d).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn input_error() {
+        let input = stream::iter(vec![
+            Ok(Bytes::from_static(b"some data")),
+            Err(io::Error::new(io::ErrorKind::Other, "oops")),
+            Ok(Bytes::from_static(b"more data")),
+        ]);
+        let mut c = compress_stream(input, CompressParams::new());
+        let err = block_on(c.next()).unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "oops");
+        assert!(block_on(c.next()).is_none());
+    }
+}
@@ -0,0 +1,3 @@
+//! Async streams for wrapping `AsyncBufRead` types as encoders/decoders
+
+pub use async_io::bufread::{BrotliDecoder, BrotliEncoder};
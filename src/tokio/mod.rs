@@ -0,0 +1,150 @@
+//! Async encoders and decoders for tokio's I/O traits
+//!
+//! The `bufread` streams read from an `AsyncBufRead` and implement
+//! `AsyncRead` and `AsyncBufRead`, and the `write` streams implement
+//! `AsyncWrite` and write to an underlying `AsyncWrite`. A plain `AsyncRead`
+//! can be used as the input of a `bufread` stream by wrapping it in a
+//! `tokio::io::BufReader`.
+//!
+//! Flushing a `write::BrotliEncoder` flushes the compressor, so everything
+//! written so far can be decompressed by the other end, and shutting it down
+//! finishes the compressed stream. Unlike the synchronous writers nothing can
+//! be done when these streams are dropped, so `shutdown` must be called to
+//! complete the output.
+//!
+//! These are the same types as in the `futures` module, and implement that
+//! module's traits as well when the `futures-io` feature is enabled.
+//!
+//! This module is only available with the `tokio` feature enabled.
+
+pub mod bufread;
+pub mod write;
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio_crate::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio_crate::runtime::{Builder, Runtime};
+
+    use super::{bufread, write};
+    use async_io::tests::{lines, random_data, Stutter};
+    use raw::{DeStatus, Decompress};
+    use read;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().build().unwrap()
+    }
+
+    #[test]
+    fn bufread_roundtrip() {
+        let rt = runtime();
+        let data = random_data();
+        let e = bufread::BrotliEncoder::new(Stutter::new(&data[..]), 6);
+        let mut d = bufread::BrotliDecoder::new(Stutter::new(BufReader::new(e)));
+        let mut result = Vec::new();
+        rt.block_on(d.read_to_end(&mut result)).unwrap();
+        assert!(result == data);
+
+        assert_eq!(d.total_out(), data.len() as u64);
+        let e = d.into_inner().inner.into_inner();
+        assert_eq!(e.total_in(), data.len() as u64);
+    }
+
+    #[test]
+    fn bufread_lines() {
+        let rt = runtime();
+        let text = lines();
+        // The streams are buffered readers themselves, so they can be chained
+        // without a `BufReader`.
+        let e = bufread::BrotliEncoder::new(Stutter::new(text.as_bytes()), 6);
+        let mut d = bufread::BrotliDecoder::new(Stutter::new(e));
+        let mut line = String::new();
+        rt.block_on(d.read_line(&mut line)).unwrap();
+        assert_eq!(line, "line 0\n");
+        let mut rest = String::new();
+        rt.block_on(d.read_to_string(&mut rest)).unwrap();
+        assert_eq!(line + &rest, text);
+    }
+
+    #[test]
+    fn bufread_matches_sync() {
+        let rt = runtime();
+        let data = random_data();
+        let mut compressed = Vec::new();
+        let mut e = bufread::BrotliEncoder::new(&data[..], 9);
+        rt.block_on(e.read_to_end(&mut compressed)).unwrap();
+
+        let mut expected = Vec::new();
+        std::io::Read::read_to_end(&mut read::BrotliEncoder::new(&data[..], 9), &mut expected)
+            .unwrap();
+        assert!(compressed == expected);
+    }
+
+    #[test]
+    fn bufread_truncated() {
+        let rt = runtime();
+        let mut compressed = Vec::new();
+        let mut e = bufread::BrotliEncoder::new(&b"truncated stream"[..], 6);
+        rt.block_on(e.read_to_end(&mut compressed)).unwrap();
+
+        let mut d = bufread::BrotliDecoder::new(&compressed[..compressed.len() - 1]);
+        let err = rt.block_on(d.read_to_end(&mut Vec::new())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_roundtrip() {
+        let rt = runtime();
+        let data = random_data();
+        let d = write::BrotliDecoder::new(Stutter::new(Vec::new()));
+        let mut e = write::BrotliEncoder::new(Stutter::new(d), 6);
+        for chunk in data.chunks(1000) {
+            rt.block_on(e.write_all(chunk)).unwrap();
+        }
+        rt.block_on(e.shutdown()).unwrap();
+        assert_eq!(e.total_in(), data.len() as u64);
+
+        let d = e.into_inner().inner;
+        assert_eq!(d.total_out(), data.len() as u64);
+        assert!(d.into_inner().inner == data);
+    }
+
+    #[test]
+    fn write_flush() {
+        let rt = runtime();
+        let mut e = write::BrotliEncoder::new(Stutter::new(Vec::new()), 6);
+        rt.block_on(e.write_all(b"hello, ")).unwrap();
+        rt.block_on(e.flush()).unwrap();
+
+        // Everything written so far can be decompressed, but the stream
+        // isn't complete yet.
+        let mut d = Decompress::new();
+        let mut out = [0; 64];
+        let status = d.decompress(&mut &e.get_ref().inner[..], &mut &mut out[..]);
+        assert_eq!(status, Ok(DeStatus::NeedInput));
+        assert_eq!(&out[..7], b"hello, ");
+
+        rt.block_on(e.write_all(b"world")).unwrap();
+        rt.block_on(e.shutdown()).unwrap();
+        let mut d = write::BrotliDecoder::new(Vec::new());
+        rt.block_on(d.write_all(&e.into_inner().inner)).unwrap();
+        rt.block_on(d.shutdown()).unwrap();
+        assert_eq!(d.get_ref(), b"hello, world");
+    }
+
+    #[test]
+    fn write_truncated() {
+        let rt = runtime();
+        let mut e = write::BrotliEncoder::new(Vec::new(), 6);
+        rt.block_on(e.write_all(b"truncated stream")).unwrap();
+        rt.block_on(e.shutdown()).unwrap();
+        let compressed = e.into_inner();
+
+        let mut d = write::BrotliDecoder::new(Vec::new());
+        rt.block_on(d.write_all(&compressed[..compressed.len() - 1]))
+            .unwrap();
+        let err = rt.block_on(d.shutdown()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -2,31 +2,28 @@
 
 use std::io;
 use std::io::prelude::*;
+use std::mem;
 
-use raw::{self, CoStatus, Compress, CompressOp, DeStatus, Decompress};
+use raw::{Compress, CompressOp, Decompress};
 
-use super::CompressParams;
-
-const BUF_SIZE: usize = 32 * 1024;
+use super::{level_params, CompressParams, DecompressParams};
+use codec::{Buffer, Decoder, Encoder};
+use dictionary::SharedDictionary;
 
 /// A compression stream which will have uncompressed data written to it and
 /// will write compressed data to an output stream.
 pub struct BrotliEncoder<W: Write> {
-    data: Compress,
+    data: Encoder,
     obj: Option<W>,
-    buf: Vec<u8>,
-    cur: usize,
-    err: Option<raw::Error>,
+    buf: Buffer,
 }
 
 /// A compression stream which will have compressed data written to it and
 /// will write uncompressed data to an output stream.
 pub struct BrotliDecoder<W: Write> {
-    data: Decompress,
+    data: Decoder,
     obj: Option<W>,
-    buf: Vec<u8>,
-    cur: usize,
-    err: Option<raw::Error>,
+    buf: Buffer,
 }
 
 impl<W: Write> BrotliEncoder<W> {
@@ -34,26 +31,38 @@ impl<W: Write> BrotliEncoder<W> {
     /// to write compress output to the give output stream.
     pub fn new(obj: W, level: u32) -> BrotliEncoder<W> {
         let mut data = Compress::new();
-        data.set_params(CompressParams::new().quality(level));
+        data.set_params(&level_params(level)).unwrap();
         BrotliEncoder {
-            data: data,
+            data: Encoder::new(data),
             obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
-            cur: 0,
-            err: None,
+            buf: Buffer::new(),
         }
     }
 
     /// Creates a new encoder with a custom `CompressParams`.
     pub fn from_params(obj: W, params: &CompressParams) -> BrotliEncoder<W> {
         let mut data = Compress::new();
-        data.set_params(params);
+        data.set_params(params).unwrap();
         BrotliEncoder {
-            data: data,
+            data: Encoder::new(data),
             obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
-            cur: 0,
-            err: None,
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Creates a new encoder with a custom `CompressParams` for an input whose
+    /// total length may be known in advance.
+    ///
+    /// If `input_len` is known it is passed to the compressor as a size hint,
+    /// which lets it size its internal buffers for the input.
+    pub fn with_size_hint(
+        obj: W,
+        params: &CompressParams,
+        input_len: Option<u64>,
+    ) -> BrotliEncoder<W> {
+        match input_len {
+            Some(len) => BrotliEncoder::from_params(obj, params.clone().size_hint(len)),
+            None => BrotliEncoder::from_params(obj, params),
         }
     }
 
@@ -70,58 +79,65 @@ impl<W: Write> BrotliEncoder<W> {
         self.obj.as_mut().unwrap()
     }
 
+    /// Returns the number of bytes of uncompressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of compressed output produced so far.
+    ///
+    /// Some of the output may still be buffered rather than written to the
+    /// underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
     fn dump(&mut self) -> io::Result<()> {
-        loop {
-            while !self.buf.is_empty() {
-                let amt = self.obj.as_mut().unwrap().write(&self.buf[self.cur..])?;
-                self.cur += amt;
-                if self.cur == self.buf.len() {
-                    self.buf.clear();
-                    self.cur = 0
-                }
-            }
-            // TODO: if we could peek, the buffer wouldn't be necessary
-            if let Some(data) = self.data.take_output(Some(BUF_SIZE)) {
-                match self.obj.as_mut().unwrap().write(data) {
-                    Ok(n) => self.buf.extend_from_slice(&data[n..]),
-                    Err(e) => {
-                        self.buf.extend_from_slice(data);
-                        return Err(e);
-                    }
-                }
-            } else {
-                break;
-            }
-        }
-        Ok(())
+        self.buf.dump(&mut self.data, self.obj.as_mut().unwrap())
     }
 
     // Flush or finish stream, also flushing underlying stream
     fn do_flush_or_finish(&mut self, finish: bool) -> io::Result<()> {
-        self.dump()?;
         let op = if finish {
             CompressOp::Finish
         } else {
             CompressOp::Flush
         };
         loop {
-            let status = match self.data.compress(op, &mut &[][..], &mut &mut [][..]) {
-                Ok(s) => s,
-                Err(err) => {
-                    self.err = Some(err.clone());
-                    return Err(err.into());
-                }
+            self.dump()?;
+            if self.data.flush(op)? {
+                self.dump()?;
+                return self.obj.as_mut().unwrap().flush();
+            }
+        }
+    }
+
+    /// Writes a metadata block to the compressed stream.
+    ///
+    /// Metadata is opaque, out-of-band data which is skipped by decoders, so
+    /// it doesn't change the decompressed output. The decoders in this crate
+    /// can't extract it, see `raw::Decompress`. Everything written so far
+    /// is flushed ahead of the block, which is then written out verbatim.
+    ///
+    /// Blocks longer than `MAX_METADATA_LEN` are rejected with an
+    /// `InvalidInput` error whose source is a `MetadataTooLong`.
+    pub fn write_metadata(&mut self, mut data: &[u8]) -> io::Result<()> {
+        self.data.check_metadata(data)?;
+        self.dump()?;
+        loop {
+            // Emit straight into the output buffer, as the compressor only
+            // moves a few bytes of metadata at a time through its own buffer.
+            // Those are left in the compressor until the next call, which
+            // copies them into the buffer ahead of the rest.
+            let emitted = {
+                let coder = &mut self.data;
+                self.buf.fill(|out| coder.emit_metadata(&mut data, out))?
             };
-            let obj = self.obj.as_mut().unwrap();
-            while let Some(data) = self.data.take_output(None) {
-                obj.write_all(data)?;
+            if emitted {
+                return self.dump();
             }
-            match status {
-                CoStatus::Finished => {
-                    obj.flush()?;
-                    return Ok(());
-                }
-                CoStatus::Unfinished => (),
+            if self.buf.is_full() {
+                self.buf.write_pending(self.obj.as_mut().unwrap())?;
             }
         }
     }
@@ -134,31 +150,28 @@ impl<W: Write> BrotliEncoder<W> {
         self.do_flush_or_finish(true)?;
         Ok(self.obj.take().unwrap())
     }
+
+    /// Resets the state of this encoder entirely, swapping out the output
+    /// stream for another.
+    ///
+    /// The current stream is finished and flushed to the current output
+    /// stream first, which is then returned. The compression parameters are
+    /// kept.
+    pub fn reset(&mut self, obj: W) -> io::Result<W> {
+        self.do_flush_or_finish(true)?;
+        self.data.reset();
+        Ok(mem::replace(self.obj.as_mut().unwrap(), obj))
+    }
 }
 
 impl<W: Write> Write for BrotliEncoder<W> {
-    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         if data.is_empty() {
             return Ok(0);
         }
-        // If the decompressor has failed at some point, this is set.
-        // Unfortunately we have no idea what status is in the compressor
-        // was in when it failed so we can't do anything except bail again.
-        if let Some(ref err) = self.err {
-            return Err(err.clone().into());
-        }
+        self.data.check()?;
         self.dump()?;
-        // Zero-length output buf to keep it all inside the compressor buffer
-        let avail_in = data.len();
-        if let Err(err) = self
-            .data
-            .compress(CompressOp::Process, &mut data, &mut &mut [][..])
-        {
-            self.err = Some(err.clone());
-            return Err(err.into());
-        }
-        assert!(avail_in != data.len());
-        Ok(avail_in - data.len())
+        self.data.write(data)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -179,11 +192,41 @@ impl<W: Write> BrotliDecoder<W> {
     /// into `obj`.
     pub fn new(obj: W) -> BrotliDecoder<W> {
         BrotliDecoder {
-            data: Decompress::new(),
+            data: Decoder::new(Decompress::new()),
+            obj: Some(obj),
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, using a custom dictionary.
+    ///
+    /// The input must have been compressed with the same dictionary.
+    pub fn with_dictionary(obj: W, dictionary: &[u8]) -> BrotliDecoder<W> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::with_dictionary(dictionary)),
+            obj: Some(obj),
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, using a shared custom dictionary.
+    pub fn with_shared_dictionary(obj: W, dictionary: &SharedDictionary) -> BrotliDecoder<W> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::with_shared_dictionary(dictionary)),
+            obj: Some(obj),
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Creates a new decoding stream which will decode all input written to it
+    /// into `obj`, with custom decompression parameters.
+    pub fn from_params(obj: W, params: &DecompressParams) -> BrotliDecoder<W> {
+        BrotliDecoder {
+            data: Decoder::new(Decompress::from_params(params)),
             obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
-            cur: 0,
-            err: None,
+            buf: Buffer::new(),
         }
     }
 
@@ -200,53 +243,29 @@ impl<W: Write> BrotliDecoder<W> {
         self.obj.as_mut().unwrap()
     }
 
+    /// Returns the number of bytes of compressed input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.data.total_in()
+    }
+
+    /// Returns the number of bytes of decompressed output produced so far.
+    ///
+    /// Some of the output may still be buffered rather than written to the
+    /// underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.data.total_out()
+    }
+
     fn dump(&mut self) -> io::Result<()> {
-        loop {
-            while !self.buf.is_empty() {
-                let amt = self.obj.as_mut().unwrap().write(&self.buf[self.cur..])?;
-                self.cur += amt;
-                if self.cur == self.buf.len() {
-                    self.buf.clear();
-                    self.cur = 0
-                }
-            }
-            // TODO: if we could peek, the buffer wouldn't be necessary
-            if let Some(data) = self.data.take_output(Some(BUF_SIZE)) {
-                self.buf.extend_from_slice(data)
-            } else {
-                break;
-            }
-        }
-        Ok(())
+        self.buf.dump(&mut self.data, self.obj.as_mut().unwrap())
     }
 
     fn do_finish(&mut self) -> io::Result<()> {
-        self.dump()?;
         loop {
-            let status = match self.data.decompress(&mut &[][..], &mut &mut [][..]) {
-                Ok(s) => s,
-                Err(err) => {
-                    self.err = Some(err.clone());
-                    return Err(err.into());
-                }
-            };
-            let obj = self.obj.as_mut().unwrap();
-            while let Some(data) = self.data.take_output(None) {
-                obj.write_all(data)?;
-            }
-            match status {
-                DeStatus::Finished => {
-                    obj.flush()?;
-                    return Ok(());
-                }
-                // When decoding a truncated file, brotli returns DeStatus::NeedInput.
-                // Since we're finishing, we cannot provide more data so this is an
-                // error.
-                DeStatus::NeedInput => {
-                    let msg = "brotli compressed stream is truncated or otherwise corrupt";
-                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
-                }
-                DeStatus::NeedOutput => (),
+            self.dump()?;
+            if self.data.finish()? {
+                self.dump()?;
+                return self.obj.as_mut().unwrap().flush();
             }
         }
     }
@@ -256,31 +275,37 @@ impl<W: Write> BrotliDecoder<W> {
         self.do_finish()?;
         Ok(self.obj.take().unwrap())
     }
+
+    /// Resets the state of this decoder entirely, swapping out the output
+    /// stream for another.
+    ///
+    /// The current stream is finished first, as with `finish`, and the current
+    /// output stream is returned. The decompression parameters are kept.
+    ///
+    /// Once `finish` has taken the output stream this decoder can't be used
+    /// anymore, and an error is returned.
+    pub fn reset(&mut self, obj: W) -> io::Result<W> {
+        if self.obj.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "brotli decoder has already been finished",
+            ));
+        }
+        self.do_finish()?;
+        self.data.reset();
+        self.buf.clear();
+        Ok(mem::replace(self.obj.as_mut().unwrap(), obj))
+    }
 }
 
 impl<W: Write> Write for BrotliDecoder<W> {
-    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         if data.is_empty() {
             return Ok(0);
         }
-        // If the decompressor has failed at some point, this is set.
-        // Unfortunately we have no idea what status is in the compressor
-        // was in when it failed so we can't do anything except bail again.
-        if let Some(ref err) = self.err {
-            return Err(err.clone().into());
-        }
+        self.data.check()?;
         self.dump()?;
-        // Zero-length output buf to keep it all inside the decompressor buffer
-        let avail_in = data.len();
-        let status = match self.data.decompress(&mut data, &mut &mut [][..]) {
-            Ok(s) => s,
-            Err(err) => {
-                self.err = Some(err.clone());
-                return Err(err.into());
-            }
-        };
-        assert!(avail_in != data.len() || status == DeStatus::Finished);
-        Ok(avail_in - data.len())
+        self.data.write(data)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -300,10 +325,14 @@ impl<W: Write> Drop for BrotliDecoder<W> {
 #[cfg(test)]
 mod tests {
     use super::{BrotliDecoder, BrotliEncoder};
+    use raw;
+    use std::io;
     use std::io::prelude::*;
     use std::iter::repeat;
+    use {CompressParams, DecompressParams, MetadataTooLong, Preset, MAX_METADATA_LEN};
 
     #[test]
+    #[allow(clippy::manual_str_repeat)]
     fn smoke() {
         let d = BrotliDecoder::new(Vec::new());
         let mut c = BrotliEncoder::new(d, 6);
@@ -317,6 +346,19 @@ mod tests {
     }
 
     #[test]
+    fn size_hint() {
+        let m = "12345".repeat(1000);
+        let d = BrotliDecoder::new(Vec::new());
+        let mut params = CompressParams::new();
+        params.disable_literal_context_modeling(true);
+        let mut c = BrotliEncoder::with_size_hint(d, &params, Some(m.len() as u64));
+        c.write_all(m.as_bytes()).unwrap();
+        let data = c.finish().unwrap().finish().unwrap();
+        assert_eq!(data, m.as_bytes());
+    }
+
+    #[test]
+    #[allow(clippy::unused_io_amount)]
     fn write_empty() {
         let d = BrotliDecoder::new(Vec::new());
         let mut c = BrotliEncoder::new(d, 6);
@@ -325,6 +367,58 @@ mod tests {
         assert_eq!(&data[..], b"");
     }
 
+    #[test]
+    fn decode_error_offset() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"hello!").unwrap();
+        c.flush().unwrap();
+        let mut data = c.get_ref().clone();
+        let n = data.len();
+        data.push(0xff);
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data[..n]).unwrap();
+        d.flush().unwrap();
+        let err = d.write_all(&data[n..]).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<raw::Error>().unwrap();
+        assert_eq!(err.input_offset(), Some(n as u64 + 1));
+        assert_eq!(err.output_offset(), Some(6));
+    }
+
+    #[test]
+    fn dictionary() {
+        let dict = b"{\"id\": 0, \"name\": \"\", \"tags\": []}";
+        let d = BrotliDecoder::with_dictionary(Vec::new(), dict);
+        let mut params = CompressParams::new();
+        params.dictionary(dict);
+        let mut c = BrotliEncoder::from_params(d, &params);
+        c.write_all(b"{\"id\": 7, \"name\": \"brotli\", \"tags\": []}")
+            .unwrap();
+        let data = c.finish().unwrap().finish().unwrap();
+        assert_eq!(
+            &data[..],
+            &b"{\"id\": 7, \"name\": \"brotli\", \"tags\": []}"[..]
+        );
+    }
+
+    #[test]
+    fn totals() {
+        let m = "totals ".repeat(10_000);
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(m.as_bytes()).unwrap();
+        c.flush().unwrap();
+        assert_eq!(c.total_in(), m.len() as u64);
+        assert_eq!(c.total_out(), c.get_ref().len() as u64);
+        let data = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        d.flush().unwrap();
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), m.len() as u64);
+        assert_eq!(d.finish().unwrap(), m.as_bytes());
+    }
+
     #[test]
     fn qc() {
         ::quickcheck::quickcheck(test as fn(_) -> _);
@@ -336,4 +430,141 @@ mod tests {
             v == w.finish().unwrap().finish().unwrap()
         }
     }
+
+    #[test]
+    fn presets() {
+        let presets = [
+            Preset::Realtime,
+            Preset::HttpDynamic,
+            Preset::HttpStatic,
+            Preset::Archive,
+            Preset::Font,
+        ];
+        let s = "<li>item</li>".repeat(1000);
+        for &preset in presets.iter() {
+            let params = CompressParams::preset(preset);
+            let d = BrotliDecoder::new(Vec::new());
+            let mut c = BrotliEncoder::from_params(d, &params);
+            c.write_all(s.as_bytes()).unwrap();
+            let data = c.finish().unwrap().finish().unwrap();
+            assert_eq!(data, s.as_bytes(), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"first stream").unwrap();
+        let first = c.reset(Vec::new()).unwrap();
+        c.write_all(b"second stream").unwrap();
+        let second = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&first).unwrap();
+        assert_eq!(d.reset(Vec::new()).unwrap(), b"first stream");
+        d.write_all(&second).unwrap();
+        assert_eq!(d.finish().unwrap(), b"second stream");
+        assert!(d.reset(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn output_limit() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(&vec![0; 1 << 20]).unwrap();
+        let data = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::from_params(
+            Vec::new(),
+            DecompressParams::new().max_output_size(64 * 1024),
+        );
+        let err = d
+            .write_all(&data)
+            .and_then(|_| d.finish().map(|_| ()))
+            .unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<raw::Error>().unwrap();
+        assert_eq!(err.limit_exceeded(), Some(raw::LimitExceeded::OutputSize));
+        assert!(d.get_ref().len() <= 64 * 1024);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"first record").unwrap();
+        c.write_metadata(b"record boundary").unwrap();
+        let n = c.get_ref().len();
+        c.write_all(b", second record").unwrap();
+        c.write_metadata(b"").unwrap();
+        c.write_metadata(&vec![b'm'; 100_000]).unwrap();
+        let data = c.finish().unwrap();
+
+        // Metadata is written out verbatim, after everything written before
+        let marker = data.windows(15).position(|w| w == b"record boundary");
+        assert_eq!(marker.map(|i| i + 15), Some(n));
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data[..n]).unwrap();
+        d.flush().unwrap();
+        assert_eq!(d.get_ref(), b"first record");
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"first record, second record");
+    }
+
+    #[test]
+    fn metadata_writes() {
+        struct Counting {
+            data: Vec<u8>,
+            writes: usize,
+        }
+
+        impl Write for Counting {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                self.data.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let w = Counting {
+            data: Vec::new(),
+            writes: 0,
+        };
+        let mut c = BrotliEncoder::new(w, 6);
+        c.write_all(b"data").unwrap();
+        c.write_metadata(&vec![b'm'; MAX_METADATA_LEN]).unwrap();
+        let w = c.finish().unwrap();
+
+        // The block is written out a buffer at a time
+        assert!(
+            w.writes <= MAX_METADATA_LEN / (32 * 1024) + 4,
+            "{}",
+            w.writes
+        );
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&w.data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"data");
+    }
+
+    #[test]
+    fn metadata_too_long() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"data").unwrap();
+        c.write_metadata(&vec![0; MAX_METADATA_LEN]).unwrap();
+        let err = c
+            .write_metadata(&vec![0; MAX_METADATA_LEN + 1])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = err.get_ref().unwrap().downcast_ref::<MetadataTooLong>();
+        assert_eq!(err.unwrap().metadata_len(), MAX_METADATA_LEN + 1);
+
+        // The stream is still usable
+        c.write_all(b" and more data").unwrap();
+        let data = c.finish().unwrap();
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"data and more data");
+    }
 }
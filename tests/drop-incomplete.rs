@@ -4,6 +4,7 @@ use brotli2::write::BrotliDecoder;
 use std::io::prelude::*;
 
 // This is a BR file generated by head -c10 /dev/urandom | bro --output file.br
+#[allow(clippy::redundant_static_lifetimes)]
 const DATA: &'static [u8] = &[
     139, 4, 128, 227, 139, 226, 91, 233, 134, 14, 218, 140, 196, 3,
 ];
@@ -21,6 +22,7 @@ fn drop_writer_incomplete_input_no_loop() {
 
 /// Same as above, but verifying that we get an error if we manually call `finish`;
 #[test]
+#[allow(clippy::err_expect)]
 fn finish_writer_incomplete_input_error() {
     let mut decoder = BrotliDecoder::new(Vec::new());
     const PREFIX_LEN: usize = 10;